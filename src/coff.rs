@@ -1,7 +1,25 @@
-use std::io::{Seek, SeekFrom, Cursor};
+use std::io::{Seek, SeekFrom};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 
+pub const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
+pub const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x00000040;
+pub const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x00000080;
+pub const IMAGE_SCN_LNK_INFO: u32 = 0x00000200;
+pub const IMAGE_SCN_LNK_REMOVE: u32 = 0x00000800;
+pub const IMAGE_SCN_LNK_COMDAT: u32 = 0x00001000;
+pub const IMAGE_SCN_ALIGN_MASK: u32 = 0x00F00000;
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+pub const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+const IMAGE_SYM_ABSOLUTE: i16 = -1;
+
+pub const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+pub const IMAGE_SYM_CLASS_STATIC: u8 = 3;
+
+const IMAGE_COMDAT_SELECT_ASSOCIATIVE: u8 = 5;
+
 pub fn read_coff<F: ReadBytesExt + Seek>(mut coff: F) -> COFF {
     let header = read_header(&mut coff);
     let section_table_position = coff.stream_position().unwrap();
@@ -43,7 +61,7 @@ pub fn read_header<F: ReadBytesExt>(coff: &mut F) -> Header {
 }
 
 pub fn read_sections<F: ReadBytesExt + Seek>(coff: &mut F, number_of_sections: u16, string_table: &[u8]) -> Vec<Section> {
-    let mut sections = Vec::with_capacity(usize::try_from(number_of_sections).unwrap());
+    let mut sections = Vec::with_capacity(usize::from(number_of_sections));
     for _ in 0..number_of_sections {
         let name = read_section_name(coff, string_table);
         let virtual_size = coff.read_u32::<LittleEndian>().unwrap();
@@ -51,17 +69,18 @@ pub fn read_sections<F: ReadBytesExt + Seek>(coff: &mut F, number_of_sections: u
         let size_of_raw_data = coff.read_u32::<LittleEndian>().unwrap();
         let pointer_to_raw_data = coff.read_u32::<LittleEndian>().unwrap();
         let pointer_to_relocations = coff.read_u32::<LittleEndian>().unwrap();
-        // TODO: actually read these values?
-        coff.seek(SeekFrom::Current(4)).unwrap();
-        // TODO: actually read these values?
+        let pointer_to_linenumbers = coff.read_u32::<LittleEndian>().unwrap();
         let number_of_relocations = coff.read_u16::<LittleEndian>().unwrap();
-        coff.seek(SeekFrom::Current(2)).unwrap();
+        let number_of_linenumbers = coff.read_u16::<LittleEndian>().unwrap();
 
         let characteristics_position = coff.stream_position().unwrap();
 
-        coff.seek(SeekFrom::Start(u64::from(pointer_to_raw_data))).unwrap();
         let mut raw_data = vec![0; usize::try_from(size_of_raw_data).unwrap()];
-        coff.read_exact(&mut raw_data).unwrap();
+        // Uninitialized data in objects has a size but nothing to read.
+        if pointer_to_raw_data != 0 {
+            coff.seek(SeekFrom::Start(u64::from(pointer_to_raw_data))).unwrap();
+            coff.read_exact(&mut raw_data).unwrap();
+        }
 
         coff.seek(SeekFrom::Start(u64::from(pointer_to_relocations))).unwrap();
         let relocations = read_relocations(coff, number_of_relocations);
@@ -72,6 +91,11 @@ pub fn read_sections<F: ReadBytesExt + Seek>(coff: &mut F, number_of_sections: u
             name,
             virtual_size,
             virtual_address,
+            pointer_to_raw_data,
+            pointer_to_relocations,
+            pointer_to_linenumbers,
+            number_of_relocations,
+            number_of_linenumbers,
             raw_data,
             relocations,
             characteristics,
@@ -112,32 +136,229 @@ pub fn write_header<F: WriteBytesExt>(header: Header, destination: &mut F) {
     destination.write_u16::<LittleEndian>(header.characteristics).unwrap();
 }
 
-pub fn write_sections<F: WriteBytesExt + Seek>(sections: &Vec<Section>, destination: &mut F) {
-    let mut current_raw_data_pointer: usize = 0x1000;
-
-    for si in 0..sections.len() {
-        let section = &sections[si];
-
-        let name_bytes = section.name[..8].as_bytes();
-        assert_eq!(name_bytes.len(), 8);
-        destination.write_all(name_bytes).unwrap();
+pub fn write_sections<F: WriteBytesExt + Seek>(sections: &[Section], destination: &mut F) {
+    for section in sections {
+        // Images have no string table, long names are cut short.
+        let mut name_bytes = [0; 8];
+        let name_length = section.name.len().min(8);
+        name_bytes[..name_length].copy_from_slice(&section.name.as_bytes()[..name_length]);
+        destination.write_all(&name_bytes).unwrap();
         destination.write_u32::<LittleEndian>(section.virtual_size).unwrap();
         destination.write_u32::<LittleEndian>(section.virtual_address).unwrap();
         destination.write_u32::<LittleEndian>(u32::try_from(section.raw_data.len()).unwrap()).unwrap();
-        assert_eq!(section.raw_data.len() % 0x1000, 0);
-        destination.write_u32::<LittleEndian>(u32::try_from(current_raw_data_pointer).unwrap()).unwrap();
-        current_raw_data_pointer += section.raw_data.len();
-        destination.write_u32::<LittleEndian>(0).unwrap();
-        // TODO: write the actual value.
-        destination.write_u32::<LittleEndian>(0).unwrap();
-        destination.write_u16::<LittleEndian>(0).unwrap();
-        // TODO: write the actual value.
-        destination.write_u16::<LittleEndian>(0).unwrap();
+        destination.write_u32::<LittleEndian>(section.pointer_to_raw_data).unwrap();
+        destination.write_u32::<LittleEndian>(section.pointer_to_relocations).unwrap();
+        destination.write_u32::<LittleEndian>(section.pointer_to_linenumbers).unwrap();
+        destination.write_u16::<LittleEndian>(section.number_of_relocations).unwrap();
+        destination.write_u16::<LittleEndian>(section.number_of_linenumbers).unwrap();
         // TODO: unset COFF flags.
         destination.write_u32::<LittleEndian>(section.characteristics).unwrap();
     }
 }
 
+/// Removes every section whose `keep` entry is false and renumbers the
+/// symbols to match. Symbols that were defined in a removed section become
+/// undefined, so nothing can resolve to them any more.
+pub fn remove_sections(coff: &mut COFF, keep: &[bool]) {
+    assert_eq!(keep.len(), coff.sections.len());
+
+    let mut section_numbers = Vec::with_capacity(keep.len());
+    let mut section_number = 0;
+    for k in keep {
+        if *k {
+            section_number += 1;
+            section_numbers.push(section_number);
+        } else {
+            section_numbers.push(0);
+        }
+    }
+
+    for symbol in &mut coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number > 0 {
+            s.section_number = section_numbers[usize::try_from(s.section_number - 1).unwrap()];
+        }
+    }
+
+    let mut si = 0;
+    coff.sections.retain(|_| {
+        si += 1;
+        keep[si - 1]
+    });
+}
+
+/// Inserts `section` at `index` and renumbers the symbols, and the sections
+/// COMDAT sections are associated with, to match.
+pub fn insert_section(coff: &mut COFF, index: usize, section: Section) {
+    let section_number = i16::try_from(index + 1).unwrap();
+    for si in 0..coff.symbols.len() {
+        match associated_section_number(coff, si) {
+            Some(n) if n >= section_number => {
+                if let SymbolTableRecord::Aux(aux) = &mut coff.symbols[si + 1] {
+                    aux[12..14].copy_from_slice(&(n + 1).to_le_bytes());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for symbol in &mut coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number >= section_number {
+            s.section_number += 1;
+        }
+    }
+    coff.sections.insert(index, section);
+}
+
+/// Returns the number of the section a COMDAT section is associated with,
+/// if `symbol_table_index` is the section definition of one.
+pub fn associated_section_number(coff: &COFF, symbol_table_index: usize) -> Option<i16> {
+    let s = match &coff.symbols[symbol_table_index] {
+        SymbolTableRecord::Symbol(s) => s,
+        SymbolTableRecord::Aux(_) => return None,
+    };
+    if s.storage_class != IMAGE_SYM_CLASS_STATIC || s.value != 0 || s.number_of_aux_symbols == 0 || s.section_number < 1 {
+        return None;
+    }
+    let aux = match &coff.symbols[symbol_table_index + 1] {
+        SymbolTableRecord::Aux(aux) => aux,
+        SymbolTableRecord::Symbol(_) => return None,
+    };
+    if aux[14] != IMAGE_COMDAT_SELECT_ASSOCIATIVE {
+        return None;
+    }
+    Some(i16::from_le_bytes([aux[12], aux[13]]))
+}
+
+/// An `/EXPORT:entryname[=internalname][,@ordinal[,NONAME]][,DATA]`.
+pub struct Export {
+    /// The name it's exported by, as given.
+    pub name: String,
+    /// The symbol it refers to: `internalname` if there is one, `name`
+    /// otherwise.
+    pub symbol: String,
+    pub ordinal: Option<u16>,
+    pub no_name: bool,
+}
+
+/// Linker directives gathered from the `.drectve` sections of an object.
+#[derive(Default)]
+pub struct Directives {
+    pub exports: Vec<Export>,
+    /// Symbol names from `/INCLUDE`.
+    pub includes: Vec<String>,
+}
+
+pub fn read_directives(coff: &COFF) -> Directives {
+    let mut directives = Directives::default();
+    for section in &coff.sections {
+        if section.characteristics & IMAGE_SCN_LNK_INFO == 0 || section.name.trim_end_matches('\0') != ".drectve" {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&section.raw_data);
+        for token in split_directives(&text) {
+            // Both MSVC's "/EXPORT:" and GCC's "-export:" spellings are in use.
+            let (option, value) = match token[1..].split_once(':') {
+                Some((option, value)) if token.starts_with(['/', '-']) => (option.to_ascii_lowercase(), value.trim_matches('"')),
+                _ => continue,
+            };
+            match option.as_str() {
+                "export" => directives.exports.push(parse_export(value)),
+                "include" => directives.includes.push(value.to_string()),
+                _ => (),
+            }
+        }
+    }
+    directives
+}
+
+/// Symbol table indices of the exception handlers the `.sxdata` sections of
+/// an object register for SafeSEH.
+pub fn read_safe_seh_handlers(coff: &COFF) -> Vec<u32> {
+    let mut handlers = Vec::new();
+    for section in &coff.sections {
+        if section.characteristics & IMAGE_SCN_LNK_INFO == 0 || section.name.trim_end_matches('\0') != ".sxdata" {
+            continue;
+        }
+        for index in section.raw_data.chunks_exact(4) {
+            handlers.push(u32::from_le_bytes(index.try_into().unwrap()));
+        }
+    }
+    handlers
+}
+
+/// Whether the compiler marked the object as listing all of its exception
+/// handlers in `.sxdata`, through bit 0 of the absolute symbol "@feat.00".
+pub fn is_safe_seh_compatible(coff: &COFF) -> bool {
+    coff.symbols.iter().any(|symbol| match symbol {
+        SymbolTableRecord::Symbol(s) => s.name == "@feat.00" && s.section_number == IMAGE_SYM_ABSOLUTE && s.value & 1 != 0,
+        SymbolTableRecord::Aux(_) => false,
+    })
+}
+
+/// The tool that built the object, from the absolute symbol "@comp.id",
+/// as the product ID and build number of a Rich header entry.
+pub fn read_comp_id(coff: &COFF) -> Option<u32> {
+    coff.symbols.iter().find_map(|symbol| match symbol {
+        SymbolTableRecord::Symbol(s) if s.name == "@comp.id" && s.section_number == IMAGE_SYM_ABSOLUTE => Some(s.value),
+        _ => None,
+    })
+}
+
+fn parse_export(value: &str) -> Export {
+    let mut parts = value.split(',');
+    let entry = parts.next().unwrap();
+    let (name, symbol) = match entry.split_once('=') {
+        Some((name, internal_name)) => (name, internal_name),
+        None => (entry, entry),
+    };
+    let mut export = Export {
+        name: name.trim_matches('"').to_string(),
+        symbol: symbol.trim_matches('"').to_string(),
+        ordinal: None,
+        no_name: false,
+    };
+    for part in parts {
+        if let Some(ordinal) = part.strip_prefix('@') {
+            export.ordinal = Some(ordinal.parse().unwrap_or_else(|_| panic!("invalid ordinal in /EXPORT:{}", value)));
+        } else if part.eq_ignore_ascii_case("NONAME") {
+            export.no_name = true;
+        }
+        // DATA and PRIVATE only matter to import libraries.
+    }
+    export
+}
+
+fn split_directives(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in text.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if (c.is_whitespace() || c == '\0') && !quoted {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+            continue;
+        }
+        token.push(c);
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct COFF {
     pub sections: Vec<Section>,
     pub symbols: Vec<SymbolTableRecord>,
@@ -146,11 +367,11 @@ pub struct COFF {
 pub struct Header {
     machine: u16,
     pub number_of_sections: u16,
-    time_date_stamp: u32,
-    pointer_to_symbol_table: u32,
+    pub time_date_stamp: u32,
+    pub pointer_to_symbol_table: u32,
     number_of_symbols: u32,
-    size_of_optional_header: u16,
-    characteristics: u16,
+    pub size_of_optional_header: u16,
+    pub characteristics: u16,
 }
 
 #[derive(Debug)]
@@ -166,14 +387,22 @@ pub struct Symbol {
     pub section_number: i16,
     pub symbol_type: u16,
     pub storage_class: u8,
-    number_of_aux_symbols: u8,
+    pub number_of_aux_symbols: u8,
 }
 
 #[derive(Default)]
 pub struct Section {
-    name: String,
+    pub name: String,
     pub virtual_size: u32,
     pub virtual_address: u32,
+    pub pointer_to_raw_data: u32,
+    /// The relocation and line number fields of the section header, written
+    /// back as they were read. The relocations the link applies are in
+    /// `relocations`; sections it creates leave these zero.
+    pub pointer_to_relocations: u32,
+    pub pointer_to_linenumbers: u32,
+    pub number_of_relocations: u16,
+    pub number_of_linenumbers: u16,
     pub raw_data: Vec<u8>,
     pub relocations: Vec<Relocation>,
     pub characteristics: u32,
@@ -193,7 +422,7 @@ fn read_symbols<F: ReadBytesExt>(coff: &mut F, number_of_symbols: u32, string_ta
         let mut name_buf = [0; 8];
         coff.read_exact(&mut name_buf).unwrap();
         let name = if name_buf[..4] != [0, 0, 0, 0] {
-            String::from_utf8_lossy(&name_buf).into_owned()
+            String::from_utf8_lossy(&name_buf).trim_end_matches('\0').to_string()
         } else {
             let offset = u32::from_le_bytes(<[u8; 4]>::try_from(&name_buf[4..]).unwrap());
             read_string(string_table, usize::try_from(offset).unwrap())
@@ -225,7 +454,7 @@ fn read_symbols<F: ReadBytesExt>(coff: &mut F, number_of_symbols: u32, string_ta
 }
 
 fn read_relocations<F: ReadBytesExt>(coff: &mut F, number_of_relocations: u16) -> Vec<Relocation> {
-    let mut relocations = Vec::with_capacity(usize::try_from(number_of_relocations).unwrap());
+    let mut relocations = Vec::with_capacity(usize::from(number_of_relocations));
     for _ in 0..number_of_relocations {
         let virtual_address = coff.read_u32::<LittleEndian>().unwrap();
         let symbol_table_index = coff.read_u32::<LittleEndian>().unwrap();
@@ -253,8 +482,7 @@ fn read_section_name<F: ReadBytesExt>(coff: &mut F, string_table: &[u8]) -> Stri
 
 fn read_string(string_table: &[u8], offset: usize) -> String {
     let mut buffer = Vec::with_capacity(64);
-    for i in offset..string_table.len() {
-        let b = string_table[i];
+    for &b in &string_table[offset..] {
         if b == 0 {
             return String::from_utf8(buffer).unwrap();
         }
@@ -0,0 +1,337 @@
+use std::io::Cursor;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+
+pub const DOS_HEADER_SIZE: usize = 0x40;
+const E_LFANEW_OFFSET: usize = 0x3c;
+
+/// "DanS" and "Rich", which start and end the Rich header.
+const DANS_SIGNATURE: u32 = 0x536E6144;
+const RICH_SIGNATURE: u32 = 0x68636952;
+
+/// What to do about the Rich header of the image.
+#[derive(Default, PartialEq)]
+pub enum Rich {
+    /// Leave it as it is.
+    Keep,
+    /// Drop it from the DOS stub.
+    Strip,
+    /// Count the object in it, by its `@comp.id`, and recompute its checksum.
+    #[default]
+    Recompute,
+}
+
+pub struct DosHeader {
+    pub e_magic: u16,
+    pub e_cblp: u16,
+    pub e_cp: u16,
+    pub e_crlc: u16,
+    pub e_cparhdr: u16,
+    pub e_minalloc: u16,
+    pub e_maxalloc: u16,
+    pub e_ss: u16,
+    pub e_sp: u16,
+    pub e_csum: u16,
+    pub e_ip: u16,
+    pub e_cs: u16,
+    pub e_lfarlc: u16,
+    pub e_ovno: u16,
+    pub e_res: [u16; 4],
+    pub e_oemid: u16,
+    pub e_oeminfo: u16,
+    pub e_res2: [u16; 10],
+    /// Where the PE signature is, derived from the size of the stub when
+    /// writing.
+    pub e_lfanew: u32,
+}
+
+/// A tool that contributed to the image, and how many times.
+#[derive(Clone, Copy, Debug)]
+pub struct CompId {
+    pub product_id: u16,
+    pub build: u16,
+    pub count: u32,
+}
+
+impl CompId {
+    fn value(&self) -> u32 {
+        (u32::from(self.product_id) << 16) | u32::from(self.build)
+    }
+}
+
+/// The undocumented list of the tools MSVC's linker leaves between the stub
+/// program and the PE signature, XORed with its checksum.
+pub struct RichHeader {
+    pub entries: Vec<CompId>,
+    /// The checksum as stored, which is also the XOR key.
+    pub checksum: u32,
+}
+
+/// Everything before the PE signature.
+pub struct Stub {
+    pub dos_header: DosHeader,
+    /// The 16-bit program that prints "This program cannot be run in DOS
+    /// mode", along with any padding up to the Rich header.
+    pub program: Vec<u8>,
+    pub rich_header: Option<RichHeader>,
+    /// Whatever is between the Rich header and the PE signature.
+    pub padding: Vec<u8>,
+}
+
+impl Stub {
+    pub fn size(&self) -> u32 {
+        let rich_header_size = self.rich_header.as_ref().map_or(0, |r| 16 + r.entries.len() * 8 + 8);
+        u32::try_from(DOS_HEADER_SIZE + self.program.len() + rich_header_size + self.padding.len()).unwrap()
+    }
+
+    /// Gives the space of the program, the Rich header and the padding over
+    /// to the PE headers. Returns the number of bytes freed.
+    pub fn truncate(&mut self) -> u32 {
+        let freed = self.size() - u32::try_from(DOS_HEADER_SIZE).unwrap();
+        self.program.clear();
+        self.rich_header = None;
+        self.padding.clear();
+        freed
+    }
+
+    /// The checksum the Rich header should have: its offset, plus the bytes
+    /// of the DOS header (but e_lfanew) and the program each rotated left by
+    /// their offset, plus each comp.id rotated left by its count.
+    pub fn rich_checksum(&self) -> Option<u32> {
+        let rich_header = self.rich_header.as_ref()?;
+        let mut bytes = Vec::new();
+        write_dos_header(&self.dos_header, &mut bytes);
+        bytes.extend(&self.program);
+
+        let mut checksum = u32::try_from(bytes.len()).unwrap();
+        for (i, b) in bytes.iter().enumerate() {
+            if (E_LFANEW_OFFSET..E_LFANEW_OFFSET + 4).contains(&i) {
+                continue;
+            }
+            checksum = checksum.wrapping_add(u32::from(*b).rotate_left(u32::try_from(i % 32).unwrap()));
+        }
+        for entry in &rich_header.entries {
+            checksum = checksum.wrapping_add(entry.value().rotate_left(entry.count % 32));
+        }
+        Some(checksum)
+    }
+
+    /// Counts one more object built by the tool `comp_id`, if given, and
+    /// stores the checksum that goes with the result. The padding makes
+    /// room for a new entry when it can.
+    pub fn recompute_rich_header(&mut self, comp_id: Option<u32>) {
+        let rich_header = match &mut self.rich_header {
+            Some(r) => r,
+            None => return,
+        };
+        if let Some(comp_id) = comp_id {
+            let product_id = u16::try_from(comp_id >> 16).unwrap();
+            let build = u16::try_from(comp_id & 0xffff).unwrap();
+            match rich_header.entries.iter_mut().find(|e| e.product_id == product_id && e.build == build) {
+                Some(entry) => entry.count += 1,
+                None => {
+                    rich_header.entries.push(CompId {product_id, build, count: 1});
+                    if self.padding.len() >= 8 && self.padding.iter().all(|&b| b == 0) {
+                        self.padding.truncate(self.padding.len() - 8);
+                    }
+                }
+            }
+        }
+        let checksum = self.rich_checksum().unwrap();
+        self.rich_header.as_mut().unwrap().checksum = checksum;
+    }
+}
+
+pub fn read_stub(stub_bytes: &[u8]) -> Stub {
+    assert!(stub_bytes.len() >= DOS_HEADER_SIZE, "the PE signature is inside the DOS header");
+    let dos_header = read_dos_header(&stub_bytes[..DOS_HEADER_SIZE]);
+    let rest = &stub_bytes[DOS_HEADER_SIZE..];
+
+    let (program, rich_header, padding) = match find_rich_header(rest) {
+        Some((start, end, rich_header)) => (rest[..start].to_vec(), Some(rich_header), rest[end..].to_vec()),
+        None => (rest.to_vec(), None, Vec::new()),
+    };
+    Stub {dos_header, program, rich_header, padding}
+}
+
+/// Finds the Rich header in what follows the DOS header, as its start, its
+/// end and its decoded contents. Anything that doesn't decode cleanly is
+/// left alone as part of the program.
+fn find_rich_header(data: &[u8]) -> Option<(usize, usize, RichHeader)> {
+    let read = |position: usize| u32::from_le_bytes(data[position..position + 4].try_into().unwrap());
+    let rich_position = (0..data.len().saturating_sub(7)).step_by(4).rev().find(|&p| read(p) == RICH_SIGNATURE)?;
+    let key = read(rich_position + 4);
+    let dans_position = (0..rich_position).step_by(4).rev().find(|&p| read(p) ^ key == DANS_SIGNATURE)?;
+    // Three zero dwords pad DanS to 16 bytes, then come 8-byte entries.
+    if dans_position + 16 > rich_position || (1..4).any(|i| read(dans_position + i * 4) != key) {
+        return None;
+    }
+    if (rich_position - dans_position - 16) % 8 != 0 {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for position in (dans_position + 16..rich_position).step_by(8) {
+        let value = read(position) ^ key;
+        entries.push(CompId {
+            product_id: u16::try_from(value >> 16).unwrap(),
+            build: u16::try_from(value & 0xffff).unwrap(),
+            count: read(position + 4) ^ key,
+        });
+    }
+    Some((dans_position, rich_position + 8, RichHeader {entries, checksum: key}))
+}
+
+pub fn write_stub(stub: &Stub, destination: &mut Vec<u8>) {
+    let start = destination.len();
+    write_dos_header(&stub.dos_header, destination);
+    let e_lfanew = stub.size();
+    destination[start + E_LFANEW_OFFSET..start + E_LFANEW_OFFSET + 4].copy_from_slice(&e_lfanew.to_le_bytes());
+    destination.extend(&stub.program);
+    if let Some(rich_header) = &stub.rich_header {
+        let key = rich_header.checksum;
+        for value in [DANS_SIGNATURE, 0, 0, 0] {
+            destination.extend((value ^ key).to_le_bytes());
+        }
+        for entry in &rich_header.entries {
+            destination.extend((entry.value() ^ key).to_le_bytes());
+            destination.extend((entry.count ^ key).to_le_bytes());
+        }
+        destination.extend(RICH_SIGNATURE.to_le_bytes());
+        destination.extend(key.to_le_bytes());
+    }
+    destination.extend(&stub.padding);
+}
+
+fn read_dos_header(header_bytes: &[u8]) -> DosHeader {
+    let mut header = Cursor::new(header_bytes);
+    let mut read_u16 = || header.read_u16::<LittleEndian>().unwrap();
+    let e_magic = read_u16();
+    assert_eq!(e_magic, 0x5a4d, "the image doesn't start with MZ");
+    let e_cblp = read_u16();
+    let e_cp = read_u16();
+    let e_crlc = read_u16();
+    let e_cparhdr = read_u16();
+    let e_minalloc = read_u16();
+    let e_maxalloc = read_u16();
+    let e_ss = read_u16();
+    let e_sp = read_u16();
+    let e_csum = read_u16();
+    let e_ip = read_u16();
+    let e_cs = read_u16();
+    let e_lfarlc = read_u16();
+    let e_ovno = read_u16();
+    let e_res = [read_u16(), read_u16(), read_u16(), read_u16()];
+    let e_oemid = read_u16();
+    let e_oeminfo = read_u16();
+    let mut e_res2 = [0; 10];
+    for r in &mut e_res2 {
+        *r = read_u16();
+    }
+    let e_lfanew = header.read_u32::<LittleEndian>().unwrap();
+
+    DosHeader {
+        e_magic,
+        e_cblp,
+        e_cp,
+        e_crlc,
+        e_cparhdr,
+        e_minalloc,
+        e_maxalloc,
+        e_ss,
+        e_sp,
+        e_csum,
+        e_ip,
+        e_cs,
+        e_lfarlc,
+        e_ovno,
+        e_res,
+        e_oemid,
+        e_oeminfo,
+        e_res2,
+        e_lfanew,
+    }
+}
+
+fn write_dos_header(header: &DosHeader, destination: &mut Vec<u8>) {
+    let fields = [
+        header.e_magic,
+        header.e_cblp,
+        header.e_cp,
+        header.e_crlc,
+        header.e_cparhdr,
+        header.e_minalloc,
+        header.e_maxalloc,
+        header.e_ss,
+        header.e_sp,
+        header.e_csum,
+        header.e_ip,
+        header.e_cs,
+        header.e_lfarlc,
+        header.e_ovno,
+    ];
+    for field in fields.iter().chain(&header.e_res).chain([header.e_oemid, header.e_oeminfo].iter()).chain(&header.e_res2) {
+        destination.write_u16::<LittleEndian>(*field).unwrap();
+    }
+    destination.write_u32::<LittleEndian>(header.e_lfanew).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_stub, write_stub, Stub};
+
+    /// The stub MSVC's linker wrote for a small console program, with a
+    /// Rich header of 11 entries at 0x80 and the key 0x61641bd7.
+    const MSVC_STUB: [u8; 0x100] = [
+        0x4d, 0x5a, 0x90, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00,
+        0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x0e, 0x1f, 0xba, 0x0e, 0x00, 0xb4, 0x09, 0xcd, 0x21, 0xb8, 0x01, 0x4c, 0xcd, 0x21, 0x54, 0x68,
+        0x69, 0x73, 0x20, 0x70, 0x72, 0x6f, 0x67, 0x72, 0x61, 0x6d, 0x20, 0x63, 0x61, 0x6e, 0x6e, 0x6f,
+        0x74, 0x20, 0x62, 0x65, 0x20, 0x72, 0x75, 0x6e, 0x20, 0x69, 0x6e, 0x20, 0x44, 0x4f, 0x53, 0x20,
+        0x6d, 0x6f, 0x64, 0x65, 0x2e, 0x0d, 0x0d, 0x0a, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x93, 0x7a, 0x0a, 0x32, 0xd7, 0x1b, 0x64, 0x61, 0xd7, 0x1b, 0x64, 0x61, 0xd7, 0x1b, 0x64, 0x61,
+        0xde, 0x63, 0xf7, 0x61, 0xc7, 0x1b, 0x64, 0x61, 0x73, 0x65, 0x65, 0x60, 0xd5, 0x1b, 0x64, 0x61,
+        0x73, 0x65, 0x99, 0x61, 0xd3, 0x1b, 0x64, 0x61, 0x73, 0x65, 0x61, 0x60, 0xc4, 0x1b, 0x64, 0x61,
+        0x73, 0x65, 0x60, 0x60, 0xdb, 0x1b, 0x64, 0x61, 0x73, 0x65, 0x67, 0x60, 0xd6, 0x1b, 0x64, 0x61,
+        0x9c, 0x63, 0x65, 0x60, 0xd4, 0x1b, 0x64, 0x61, 0xd7, 0x1b, 0x65, 0x61, 0x95, 0x1b, 0x64, 0x61,
+        0xc3, 0x64, 0x60, 0x60, 0xd6, 0x1b, 0x64, 0x61, 0xc3, 0x64, 0x9b, 0x61, 0xd6, 0x1b, 0x64, 0x61,
+        0xc3, 0x64, 0x66, 0x60, 0xd6, 0x1b, 0x64, 0x61, 0x52, 0x69, 0x63, 0x68, 0xd7, 0x1b, 0x64, 0x61,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn written(stub: &Stub) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_stub(stub, &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn msvc_stub_round_trips() {
+        let stub = read_stub(&MSVC_STUB);
+        let rich_header = stub.rich_header.as_ref().unwrap();
+        assert_eq!(rich_header.entries.len(), 11);
+        assert_eq!(rich_header.checksum, 0x61641bd7);
+        assert_eq!(stub.program.len(), 0x40);
+        assert_eq!(stub.padding.len(), 0x10);
+        assert_eq!(written(&stub), MSVC_STUB);
+    }
+
+    #[test]
+    fn rich_checksum_matches_msvc() {
+        let stub = read_stub(&MSVC_STUB);
+        assert_eq!(stub.rich_checksum(), Some(0x61641bd7));
+    }
+
+    #[test]
+    fn rich_header_with_a_partial_entry_is_left_alone() {
+        // "Rich" and the key moved 4 bytes up, into the last entry.
+        let mut stub_bytes = MSVC_STUB;
+        stub_bytes.copy_within(0xe8..0xf0, 0xe4);
+        stub_bytes[0xec..0xf0].fill(0);
+        let stub = read_stub(&stub_bytes);
+        assert!(stub.rich_header.is_none());
+        assert_eq!(written(&stub), stub_bytes);
+    }
+}
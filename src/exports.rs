@@ -0,0 +1,241 @@
+use super::coff::{
+    Section,
+    SymbolTableRecord,
+    IMAGE_SCN_CNT_CODE,
+    IMAGE_SCN_CNT_INITIALIZED_DATA,
+    IMAGE_SCN_MEM_READ,
+    IMAGE_SYM_CLASS_EXTERNAL,
+    create_symbol_for_table,
+};
+use super::pe::{DataDirectory, DirectoryEntry, Image};
+
+const EXPORT_DIRECTORY_SIZE: u32 = 40;
+
+/// Where an entry of the export address table leads.
+#[derive(Clone)]
+pub enum ExportAddress {
+    /// A slot no ordinal is assigned to.
+    Unused,
+    Rva(u32),
+    /// "DLL.function" or "DLL.#ordinal" in another module.
+    Forwarder(String),
+}
+
+/// The export directory, with its tables taken apart.
+pub struct ExportTable {
+    pub characteristics: u32,
+    pub time_date_stamp: u32,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub dll_name: String,
+    pub base: u32,
+    /// By ordinal, starting at `base`.
+    pub functions: Vec<ExportAddress>,
+    /// Names and the index into `functions` they're for.
+    pub names: Vec<(String, u32)>,
+}
+
+impl ExportTable {
+    pub fn new(dll_name: String) -> ExportTable {
+        ExportTable {
+            characteristics: 0,
+            time_date_stamp: 0,
+            major_version: 0,
+            minor_version: 0,
+            dll_name,
+            base: 1,
+            functions: Vec::new(),
+            names: Vec::new(),
+        }
+    }
+
+    /// Exports `rva` by `name`, unless it's None, and by `ordinal`, or the
+    /// ordinal after the last one if that's None too.
+    pub fn add(&mut self, name: Option<&str>, rva: u32, ordinal: Option<u16>) {
+        if let Some(name) = name {
+            assert!(!self.names.iter().any(|(n, _)| n == name), "{} is already exported", name);
+        }
+        let index = match ordinal.map(u32::from) {
+            Some(ordinal) => {
+                assert_ne!(ordinal, 0, "ordinal 0 can't be exported");
+                if ordinal < self.base {
+                    // Make room in front by lowering the base.
+                    let shift = self.base - ordinal;
+                    self.functions.splice(0..0, vec![ExportAddress::Unused; usize::try_from(shift).unwrap()]);
+                    for (_, index) in &mut self.names {
+                        *index += shift;
+                    }
+                    self.base = ordinal;
+                }
+                let index = ordinal - self.base;
+                let slot = usize::try_from(index).unwrap();
+                if slot >= self.functions.len() {
+                    self.functions.resize(slot + 1, ExportAddress::Unused);
+                }
+                assert!(matches!(self.functions[slot], ExportAddress::Unused), "ordinal {} is already exported", ordinal);
+                index
+            }
+            None => u32::try_from(self.functions.len()).unwrap(),
+        };
+        let slot = usize::try_from(index).unwrap();
+        if slot == self.functions.len() {
+            self.functions.push(ExportAddress::Unused);
+        }
+        self.functions[slot] = ExportAddress::Rva(rva);
+        if let Some(name) = name {
+            self.names.push((name.to_string(), index));
+        }
+    }
+}
+
+/// The name a symbol is exported by, the way MSVC's linker derives it on
+/// x86: the C decoration goes, but stdcall names are exported decorated.
+pub fn undecorate(symbol_name: &str) -> &str {
+    match symbol_name.strip_prefix('_') {
+        Some(name) if !symbol_name.contains('@') => name,
+        _ => symbol_name,
+    }
+}
+
+pub fn read_export_table(image: &Image) -> Option<ExportTable> {
+    let directory = image.directory(DirectoryEntry::Export)?;
+    let rva = directory.virtual_address;
+    let field = |offset: u32| image.read_u32(rva + offset);
+
+    let base = field(16);
+    let number_of_functions = field(20);
+    let number_of_names = field(24);
+    let address_of_functions = field(28);
+    let address_of_names = field(32);
+    let address_of_name_ordinals = field(36);
+
+    let is_forwarder = |function_rva: u32| function_rva >= rva && function_rva < rva + directory.size;
+    let functions = (0..number_of_functions).map(|i| match image.read_u32(address_of_functions + i * 4) {
+        0 => ExportAddress::Unused,
+        function_rva if is_forwarder(function_rva) => ExportAddress::Forwarder(image.read_cstring(function_rva)),
+        function_rva => ExportAddress::Rva(function_rva),
+    }).collect();
+    let names = (0..number_of_names).map(|i| {
+        let name = image.read_cstring(image.read_u32(address_of_names + i * 4));
+        let index = u16::from_le_bytes(image.section_data(address_of_name_ordinals + i * 2, 2).unwrap().try_into().unwrap());
+        (name, u32::from(index))
+    }).collect();
+
+    let versions = image.section_data(rva + 8, 4).expect("the export directory isn't in initialized data");
+    Some(ExportTable {
+        characteristics: field(0),
+        time_date_stamp: field(4),
+        major_version: u16::from_le_bytes([versions[0], versions[1]]),
+        minor_version: u16::from_le_bytes([versions[2], versions[3]]),
+        dll_name: image.read_cstring(field(12)),
+        base,
+        functions,
+        names,
+    })
+}
+
+/// Defines a symbol for every named export of the image, by its name and,
+/// unless the name is decorated already, with the C decoration too. Names
+/// image_info or the object already define are left alone, and so are
+/// forwarders, which lead to other modules. Returns the number of symbols
+/// added.
+pub fn add_export_symbols(image: &Image, symbol_table: &mut Vec<SymbolTableRecord>) -> usize {
+    let table = match read_export_table(image) {
+        Some(t) => t,
+        None => return 0,
+    };
+    let mut added = 0;
+    for (name, index) in &table.names {
+        let rva = match table.functions.get(usize::try_from(*index).unwrap()) {
+            Some(ExportAddress::Rva(rva)) => *rva,
+            _ => continue,
+        };
+        let si = image.section_index_for_rva(rva).unwrap_or_else(|| panic!("export {} isn't in a section", name));
+        let section = &image.sections[si];
+        let is_function = section.characteristics & IMAGE_SCN_CNT_CODE != 0;
+
+        let mut symbol_names = vec![name.clone()];
+        if !name.starts_with(['_', '?', '@']) {
+            symbol_names.push(format!("_{}", name));
+        }
+        for symbol_name in symbol_names {
+            let is_defined = symbol_table.iter().any(|symbol| matches!(symbol, SymbolTableRecord::Symbol(s) if s.name == symbol_name && s.section_number > 0 && s.storage_class == IMAGE_SYM_CLASS_EXTERNAL));
+            if !is_defined {
+                symbol_table.push(create_symbol_for_table(symbol_name, rva - section.virtual_address, i16::try_from(si + 1).unwrap(), is_function));
+                added += 1;
+            }
+        }
+    }
+    added
+}
+
+/// Writes `table` to a new ".edata" section and points the export
+/// directory at it. The names are sorted, since the loader looks them up by
+/// binary search. Returns the index of the section.
+pub fn write_export_table(image: &mut Image, table: &mut ExportTable) -> usize {
+    table.names.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+
+    let number_of_functions = u32::try_from(table.functions.len()).unwrap();
+    let number_of_names = u32::try_from(table.names.len()).unwrap();
+    let address_of_functions = EXPORT_DIRECTORY_SIZE;
+    let address_of_names = address_of_functions + number_of_functions * 4;
+    let address_of_name_ordinals = address_of_names + number_of_names * 4;
+    let strings_offset = address_of_name_ordinals + number_of_names * 2;
+
+    let virtual_address = image.next_virtual_address();
+    let mut strings = Vec::new();
+    let mut add_string = |s: &str| {
+        let rva = virtual_address + strings_offset + u32::try_from(strings.len()).unwrap();
+        strings.extend(s.as_bytes());
+        strings.push(0);
+        rva
+    };
+
+    let name_rva = add_string(&table.dll_name);
+    let mut raw_data = Vec::new();
+    for field in [table.characteristics, table.time_date_stamp] {
+        raw_data.extend(field.to_le_bytes());
+    }
+    raw_data.extend(table.major_version.to_le_bytes());
+    raw_data.extend(table.minor_version.to_le_bytes());
+    for field in [
+        name_rva,
+        table.base,
+        number_of_functions,
+        number_of_names,
+        virtual_address + address_of_functions,
+        if number_of_names == 0 { 0 } else { virtual_address + address_of_names },
+        if number_of_names == 0 { 0 } else { virtual_address + address_of_name_ordinals },
+    ] {
+        raw_data.extend(field.to_le_bytes());
+    }
+    for function in &table.functions {
+        let function_rva = match function {
+            ExportAddress::Unused => 0,
+            ExportAddress::Rva(rva) => *rva,
+            ExportAddress::Forwarder(forwarder) => add_string(forwarder),
+        };
+        raw_data.extend(function_rva.to_le_bytes());
+    }
+    let name_rvas = table.names.iter().map(|(name, _)| add_string(name)).collect::<Vec<u32>>();
+    for rva in name_rvas {
+        raw_data.extend(rva.to_le_bytes());
+    }
+    for (_, index) in &table.names {
+        raw_data.extend(u16::try_from(*index).unwrap().to_le_bytes());
+    }
+    raw_data.extend(strings);
+
+    // Forwarders are told apart by pointing into the directory, so its size
+    // covers the strings too.
+    let size = u32::try_from(raw_data.len()).unwrap();
+    let si = image.append_section(Section {
+        name: ".edata".to_string(),
+        raw_data,
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+        ..Default::default()
+    });
+    assert_eq!(image.sections[si].virtual_address, virtual_address);
+    image.set_directory(DirectoryEntry::Export, DataDirectory {virtual_address, size});
+    si
+}
@@ -0,0 +1,71 @@
+use super::coff::{COFF, SymbolTableRecord, IMAGE_SCN_LNK_INFO, IMAGE_SCN_LNK_REMOVE, IMAGE_SYM_CLASS_EXTERNAL, associated_section_number, remove_sections};
+
+pub struct Collection {
+    pub sections: usize,
+    pub bytes: usize,
+}
+
+/// Removes every section of `coff` that can't be reached by following
+/// relocations from the external symbols named in `roots`. Associative
+/// COMDAT sections are kept for as long as the section they're associated
+/// with is.
+pub fn collect_garbage(coff: &mut COFF, roots: &[String]) -> Collection {
+    let section_count = coff.sections.len();
+
+    let mut associated = vec![Vec::new(); section_count];
+    for si in 0..coff.symbols.len() {
+        let parent_section_number = match associated_section_number(coff, si) {
+            Some(n) => n,
+            None => continue,
+        };
+        let child_section_number = match &coff.symbols[si] {
+            SymbolTableRecord::Symbol(s) => s.section_number,
+            SymbolTableRecord::Aux(_) => unreachable!(),
+        };
+        associated[usize::try_from(parent_section_number - 1).unwrap()].push(usize::try_from(child_section_number - 1).unwrap());
+    }
+
+    let mut worklist = Vec::new();
+    for symbol in &coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number > 0 && s.storage_class == IMAGE_SYM_CLASS_EXTERNAL && roots.contains(&s.name) {
+            worklist.push(usize::try_from(s.section_number - 1).unwrap());
+        }
+    }
+
+    let mut reachable = vec![false; section_count];
+    while let Some(si) = worklist.pop() {
+        if reachable[si] {
+            continue;
+        }
+        reachable[si] = true;
+
+        for relocation in &coff.sections[si].relocations {
+            match &coff.symbols[usize::try_from(relocation.symbol_table_index).unwrap()] {
+                SymbolTableRecord::Symbol(s) if s.section_number > 0 => worklist.push(usize::try_from(s.section_number - 1).unwrap()),
+                // Undefined symbols are resolved against the image, not this object.
+                SymbolTableRecord::Symbol(_) => (),
+                SymbolTableRecord::Aux(_) => panic!("relocation refers to an aux symbol"),
+            }
+        }
+        worklist.extend(&associated[si]);
+    }
+
+    let mut collection = Collection {sections: 0, bytes: 0};
+    for (si, section) in coff.sections.iter().enumerate() {
+        // Sections the link discards anyway aren't garbage collection's to count.
+        if section.raw_data.is_empty() || section.characteristics & (IMAGE_SCN_LNK_INFO | IMAGE_SCN_LNK_REMOVE) != 0 {
+            reachable[si] = true;
+        }
+        if !reachable[si] {
+            collection.sections += 1;
+            collection.bytes += section.raw_data.len();
+        }
+    }
+
+    remove_sections(coff, &reachable);
+    collection
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use super::coff::{
+    COFF,
+    SymbolTableRecord,
+    IMAGE_SCN_CNT_CODE,
+    IMAGE_SCN_CNT_INITIALIZED_DATA,
+    IMAGE_SCN_LNK_COMDAT,
+    IMAGE_SCN_LNK_REMOVE,
+    IMAGE_SCN_MEM_WRITE,
+    associated_section_number,
+    remove_sections,
+};
+use super::layout::{group_name, group_suffix};
+
+pub struct Folding {
+    pub sections: usize,
+    pub bytes: usize,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Target {
+    /// A symbol defined in the object, by the section it's in (after folding)
+    /// and its offset there.
+    Section(usize, u32),
+    /// An undefined symbol, resolved by name later on.
+    External(String),
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct Key<'a> {
+    /// Sections only fold into one that lands in the same place of the
+    /// same output section.
+    group_name: &'a str,
+    group_suffix: &'a str,
+    characteristics: u32,
+    raw_data: &'a [u8],
+    relocations: Vec<(u32, u16, Target)>,
+}
+
+/// Folds byte-identical COMDAT code and read-only data sections of `coff`
+/// with the same name whose relocations point at the same targets, redirecting the symbols of every
+/// folded copy to the section that survives. Folding two sections can make
+/// the sections referring to them identical, so this repeats until nothing
+/// changes. Other sections may be told apart by their addresses, like the
+/// ".CRT$XCA" and ".CRT$XCZ" markers around a table, so they're left alone.
+pub fn fold_identical_sections(coff: &mut COFF) -> Folding {
+    let section_count = coff.sections.len();
+    let mut survivors = (0..section_count).collect::<Vec<usize>>();
+
+    loop {
+        let mut folded = false;
+        let mut keys = HashMap::new();
+        for si in 0..section_count {
+            if survivors[si] != si || !is_foldable(coff, si) {
+                continue;
+            }
+            let key = make_key(coff, si, &survivors);
+            match keys.get(&key) {
+                Some(&survivor) => {
+                    survivors[si] = survivor;
+                    folded = true;
+                }
+                None => {
+                    keys.insert(key, si);
+                }
+            }
+        }
+        if !folded {
+            break;
+        }
+    }
+
+    for symbol in &mut coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number > 0 {
+            let survivor = survivors[usize::try_from(s.section_number - 1).unwrap()];
+            s.section_number = i16::try_from(survivor + 1).unwrap();
+        }
+    }
+
+    let mut keep = (0..section_count).map(|si| survivors[si] == si).collect::<Vec<bool>>();
+    // Associative sections (unwind and debug information, mostly) go with
+    // the copy they belong to.
+    for si in 0..coff.symbols.len() {
+        let parent_section_number = match associated_section_number(coff, si) {
+            Some(n) => n,
+            None => continue,
+        };
+        if !keep[usize::try_from(parent_section_number - 1).unwrap()] {
+            if let SymbolTableRecord::Symbol(s) = &coff.symbols[si] {
+                keep[usize::try_from(s.section_number - 1).unwrap()] = false;
+            }
+        }
+    }
+
+    let mut folding = Folding {sections: 0, bytes: 0};
+    for (si, section) in coff.sections.iter().enumerate() {
+        if !keep[si] {
+            folding.sections += 1;
+            folding.bytes += section.raw_data.len();
+        }
+    }
+
+    remove_sections(coff, &keep);
+    folding
+}
+
+fn is_foldable(coff: &COFF, si: usize) -> bool {
+    let section = &coff.sections[si];
+    if section.raw_data.is_empty() || section.characteristics & IMAGE_SCN_LNK_REMOVE != 0 || section.characteristics & IMAGE_SCN_LNK_COMDAT == 0 {
+        return false;
+    }
+    section.characteristics & IMAGE_SCN_CNT_CODE != 0
+        || (section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 && section.characteristics & IMAGE_SCN_MEM_WRITE == 0)
+}
+
+fn make_key<'a>(coff: &'a COFF, si: usize, survivors: &[usize]) -> Key<'a> {
+    let section = &coff.sections[si];
+    let relocations = section.relocations.iter().map(|relocation| {
+        let target = match &coff.symbols[usize::try_from(relocation.symbol_table_index).unwrap()] {
+            SymbolTableRecord::Symbol(s) if s.section_number > 0 => {
+                Target::Section(survivors[usize::try_from(s.section_number - 1).unwrap()], s.value)
+            }
+            SymbolTableRecord::Symbol(s) => Target::External(s.name.clone()),
+            SymbolTableRecord::Aux(_) => panic!("relocation refers to an aux symbol"),
+        };
+        (relocation.virtual_address, relocation.relocation_type, target)
+    }).collect();
+
+    Key {
+        group_name: group_name(&section.name),
+        group_suffix: group_suffix(&section.name),
+        characteristics: section.characteristics,
+        raw_data: &section.raw_data,
+        relocations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::coff::{
+        COFF,
+        Section,
+        IMAGE_SCN_CNT_INITIALIZED_DATA,
+        IMAGE_SCN_LNK_COMDAT,
+        IMAGE_SCN_MEM_READ,
+        create_symbol_for_table,
+    };
+    use super::fold_identical_sections;
+
+    fn data_section(name: &str, characteristics: u32) -> Section {
+        Section {
+            name: name.to_string(),
+            raw_data: vec![0; 4],
+            characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | characteristics,
+            ..Default::default()
+        }
+    }
+
+    fn fold(sections: Vec<Section>) -> usize {
+        let symbols = (0..sections.len())
+            .map(|si| create_symbol_for_table(format!("_symbol{}", si), 0, i16::try_from(si + 1).unwrap(), false))
+            .collect();
+        let mut coff = COFF {sections, symbols};
+        fold_identical_sections(&mut coff).sections
+    }
+
+    #[test]
+    fn identical_comdat_sections_fold() {
+        assert_eq!(fold(vec![data_section(".rdata", IMAGE_SCN_LNK_COMDAT), data_section(".rdata", IMAGE_SCN_LNK_COMDAT)]), 1);
+    }
+
+    #[test]
+    fn identical_sections_that_are_not_comdat_stay_apart() {
+        assert_eq!(fold(vec![data_section(".rdata$x", 0), data_section(".rdata$x", 0)]), 0);
+    }
+
+    #[test]
+    fn identical_comdat_sections_of_different_groups_stay_apart() {
+        assert_eq!(fold(vec![data_section(".rdata$a", IMAGE_SCN_LNK_COMDAT), data_section(".rdata$z", IMAGE_SCN_LNK_COMDAT)]), 0);
+    }
+}
@@ -1,26 +1,70 @@
 use serde::Deserialize;
 
-use super::coff::{SymbolTableRecord, Section, Relocation, create_symbol_for_relocation, create_symbol_for_table};
-use super::pe::Image;
+use super::coff::{Export, SymbolTableRecord, Relocation, create_symbol_for_relocation, create_symbol_for_table};
+use super::imports::OrdinalImport;
+use super::pe::{Image, HeaderOverrides, parse_subsystem, parse_subsystem_version};
 
 pub fn fill_image_and_symbol_table_with_image_info(image: &mut Image, symbol_table: &mut Vec<SymbolTableRecord>, image_info_str: &str) {
     let image_info: ImageInfo = toml::from_str(image_info_str).unwrap();
-    fill_image_relocations_and_symbol_table_with_image_info(image_info.relocations, &mut image.sections, symbol_table);
-    fill_symbol_table_with_image_info(image_info.symbols, &mut image.sections, symbol_table);
+    fill_image_relocations_and_symbol_table_with_image_info(image_info.relocations, image, symbol_table);
+    fill_symbol_table_with_image_info(image_info.symbols, image, symbol_table);
 }
 
-fn fill_image_relocations_and_symbol_table_with_image_info(relocations: Vec<ImageInfoRelocation>, image_sections: &mut Vec<Section>, symbol_table: &mut Vec<SymbolTableRecord>) {
+/// The header fields the optional `[header]` table of image_info sets.
+pub fn read_header_overrides(image_info_str: &str) -> HeaderOverrides {
+    let image_info: ImageInfo = toml::from_str(image_info_str).unwrap();
+    let header = match image_info.header {
+        Some(h) => h,
+        None => return HeaderOverrides::default(),
+    };
+    HeaderOverrides {
+        stack_reserve: header.stack_reserve,
+        stack_commit: header.stack_commit,
+        heap_reserve: header.heap_reserve,
+        heap_commit: header.heap_commit,
+        subsystem: header.subsystem.as_deref().map(parse_subsystem),
+        subsystem_version: header.subsystem_version.as_deref().map(parse_subsystem_version),
+        time_date_stamp: header.timestamp,
+    }
+}
+
+/// The exports listed in the optional `[[exports]]` array of image_info.
+pub fn read_exports(image_info_str: &str) -> Vec<Export> {
+    let image_info: ImageInfo = toml::from_str(image_info_str).unwrap();
+    image_info.exports.into_iter().map(|export| Export {
+        symbol: export.symbol.unwrap_or_else(|| export.name.clone()),
+        name: export.name,
+        ordinal: export.ordinal,
+        no_name: export.noname,
+    }).collect()
+}
+
+/// Names for the imports by ordinal listed in the optional `[[imports]]`
+/// array of image_info.
+pub fn read_ordinal_imports(image_info_str: &str) -> Vec<OrdinalImport> {
+    let image_info: ImageInfo = toml::from_str(image_info_str).unwrap();
+    image_info.imports.into_iter().map(|import| OrdinalImport {
+        name: import.name,
+        dll_name: import.dll,
+        ordinal: import.ordinal,
+    }).collect()
+}
+
+fn fill_image_relocations_and_symbol_table_with_image_info(relocations: Vec<ImageInfoRelocation>, image: &mut Image, symbol_table: &mut Vec<SymbolTableRecord>) {
     for relocation in relocations {
-        let section_number = find_section_number_for_virtual_address(relocation.virtual_address, image_sections);
-        let section = &mut image_sections[usize::try_from(section_number).unwrap() - 1];
+        let (section_number, virtual_address) = relocation.address.resolve(image);
+        let si = usize::try_from(section_number).unwrap() - 1;
 
-        // TODO: actually query the imagebase.
-        let virtual_address = relocation.virtual_address - 0x400000 - section.virtual_address;
         let symbol_table_index = symbol_table.len();
         symbol_table.push(create_symbol_for_relocation(relocation.name));
         let relocation_type = relocation.relocation_type;
+        // Whatever the field holds now would be taken for an addend.
+        let rva = image.sections[si].virtual_address + virtual_address;
+        image.section_data_mut(rva, 4)
+            .unwrap_or_else(|| panic!("relocation {} at RVA {:#010x} isn't in initialized data", symbol_table_index, rva))
+            .fill(0);
 
-        section.relocations.push(Relocation {
+        image.sections[si].relocations.push(Relocation {
             virtual_address,
             symbol_table_index: u32::try_from(symbol_table_index).unwrap(),
             relocation_type,
@@ -28,42 +72,104 @@ fn fill_image_relocations_and_symbol_table_with_image_info(relocations: Vec<Imag
     }
 }
 
-fn fill_symbol_table_with_image_info(symbols: Vec<ImageInfoSymbol>, image_sections: &Vec<Section>, symbol_table: &mut Vec<SymbolTableRecord>) {
+fn fill_symbol_table_with_image_info(symbols: Vec<ImageInfoSymbol>, image: &Image, symbol_table: &mut Vec<SymbolTableRecord>) {
     for symbol in symbols {
-        let section_number = find_section_number_for_virtual_address(symbol.virtual_address, image_sections);
-        // TODO: actually query the imagebase.
-        let value = symbol.virtual_address - 0x400000 - image_sections[usize::try_from(section_number).unwrap() - 1].virtual_address;
+        let (section_number, value) = symbol.address.resolve(image);
         symbol_table.push(create_symbol_for_table(symbol.name, value, section_number, symbol.is_function));
     }
 }
 
-fn find_section_number_for_virtual_address(address: u32, image_sections: &Vec<Section>) -> i16 {
-    let mut i = 1;
-    for section in image_sections {
-        if address > section.virtual_address && address < (section.virtual_address + section.virtual_size) {
-            return i;
-        }
-        i += 1;
+impl ImageInfoAddress {
+    /// The number of the section the address is in and its offset there.
+    fn resolve(&self, image: &Image) -> (i16, u32) {
+        let given = [self.virtual_address.is_some(), self.rva.is_some(), self.file_offset.is_some(), self.section.is_some()];
+        assert_eq!(given.iter().filter(|&&g| g).count(), 1,
+                   "expected exactly one of virtual_address, rva, file_offset or section, got {:?}", self);
+        assert!(self.offset.is_none() || self.section.is_some(), "offset only goes with section, got {:?}", self);
+
+        let rva = if let Some(name) = &self.section {
+            let section = image.sections.iter().find(|s| s.name.trim_end_matches('\0') == name)
+                .unwrap_or_else(|| panic!("there's no section {} in the image", name));
+            let offset = self.offset.unwrap_or(0);
+            let extent = section.virtual_size.max(u32::try_from(section.raw_data.len()).unwrap());
+            assert!(offset < extent, "offset {:#x} is past the end of section {}, which is {:#x} bytes long, recheck the value", offset, name, extent);
+            section.virtual_address + offset
+        } else if let Some(file_offset) = self.file_offset {
+            image.file_offset_to_rva(file_offset)
+                .unwrap_or_else(|| panic!("file offset {:#010x} isn't mapped, recheck the value", file_offset))
+        } else if let Some(virtual_address) = self.virtual_address {
+            image.va_to_rva(virtual_address)
+                .unwrap_or_else(|| panic!("{:#010x} is below the image base, recheck the value", virtual_address))
+        } else {
+            self.rva.unwrap()
+        };
+        let si = image.section_index_for_rva(rva)
+            .unwrap_or_else(|| panic!("failed to find the section of RVA {:#010x}, recheck the value", rva));
+        (i16::try_from(si + 1).unwrap(), rva - image.sections[si].virtual_address)
     }
-    panic!("failed to find the section of {:#010x}, recheck the value", address)
 }
 
 #[derive(Deserialize, Debug)]
 struct ImageInfo {
     relocations: Vec<ImageInfoRelocation>,
     symbols: Vec<ImageInfoSymbol>,
+    header: Option<ImageInfoHeader>,
+    #[serde(default)]
+    exports: Vec<ImageInfoExport>,
+    #[serde(default)]
+    imports: Vec<ImageInfoImport>,
 }
 
 #[derive(Deserialize, Debug)]
 struct ImageInfoRelocation {
     name: String,
-    virtual_address: u32,
+    #[serde(flatten)]
+    address: ImageInfoAddress,
     relocation_type: u16,
 }
 
 #[derive(Deserialize, Debug)]
 struct ImageInfoSymbol {
     name: String,
-    virtual_address: u32,
+    #[serde(flatten)]
+    address: ImageInfoAddress,
     is_function: bool,
 }
+
+/// Where a relocation or symbol is, given exactly one way: as a virtual
+/// address, an RVA, a file offset or an offset into a section by name.
+#[derive(Deserialize, Debug)]
+struct ImageInfoAddress {
+    virtual_address: Option<u32>,
+    rva: Option<u32>,
+    file_offset: Option<u32>,
+    section: Option<String>,
+    offset: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageInfoHeader {
+    stack_reserve: Option<u32>,
+    stack_commit: Option<u32>,
+    heap_reserve: Option<u32>,
+    heap_commit: Option<u32>,
+    subsystem: Option<String>,
+    subsystem_version: Option<String>,
+    timestamp: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageInfoExport {
+    name: String,
+    symbol: Option<String>,
+    ordinal: Option<u16>,
+    #[serde(default)]
+    noname: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageInfoImport {
+    name: String,
+    dll: String,
+    ordinal: u16,
+}
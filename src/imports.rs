@@ -0,0 +1,165 @@
+use super::coff::{
+    Section,
+    SymbolTableRecord,
+    IMAGE_SCN_CNT_CODE,
+    IMAGE_SCN_MEM_EXECUTE,
+    IMAGE_SCN_MEM_READ,
+    IMAGE_SYM_CLASS_EXTERNAL,
+    create_symbol_for_table,
+};
+use super::pe::{DirectoryEntry, Image};
+
+const IMPORT_DESCRIPTOR_SIZE: u32 = 20;
+const IMAGE_ORDINAL_FLAG32: u32 = 0x80000000;
+/// The prefix of the symbol that names the IAT slot of an import.
+const IMPORT_POINTER_PREFIX: &str = "__imp_";
+/// `jmp dword ptr [slot]`, followed by the address of the slot.
+const THUNK_OPCODE: [u8; 2] = [0xff, 0x25];
+const THUNK_SIZE: usize = 8;
+
+/// A function the image imports.
+pub struct Import {
+    pub dll_name: String,
+    /// None for imports by ordinal.
+    pub name: Option<String>,
+    pub ordinal: Option<u16>,
+    /// The RVA of its slot in the import address table.
+    pub iat_slot: u32,
+}
+
+/// A name for an import by ordinal, which has none in the image.
+pub struct OrdinalImport {
+    pub name: String,
+    pub dll_name: String,
+    pub ordinal: u16,
+}
+
+/// What `resolve_imports` defined.
+pub struct ImportResolution {
+    pub slots: usize,
+    pub thunks: usize,
+    /// RVAs of the addresses in the thunks, which need base relocations.
+    pub absolute_sites: Vec<u32>,
+}
+
+pub fn read_imports(image: &Image) -> Vec<Import> {
+    let directory = match image.directory(DirectoryEntry::Import) {
+        Some(d) => d,
+        None => return Vec::new(),
+    };
+    let mut imports = Vec::new();
+    let mut descriptor_rva = directory.virtual_address;
+    loop {
+        let field = |offset: u32| image.read_u32(descriptor_rva + offset);
+        let (original_first_thunk, name, first_thunk) = (field(0), field(12), field(16));
+        if name == 0 && first_thunk == 0 {
+            break;
+        }
+        let dll_name = image.read_cstring(name);
+        // Some linkers leave out the lookup table, then the IAT, unbound,
+        // stands in for it.
+        let lookup_table = if original_first_thunk != 0 { original_first_thunk } else { first_thunk };
+        for i in 0.. {
+            let entry = image.read_u32(lookup_table + i * 4);
+            if entry == 0 {
+                break;
+            }
+            let (name, ordinal) = if entry & IMAGE_ORDINAL_FLAG32 != 0 {
+                (None, Some(u16::try_from(entry & 0xffff).unwrap()))
+            } else {
+                // Skip the hint of IMAGE_IMPORT_BY_NAME.
+                (Some(image.read_cstring(entry + 2)), None)
+            };
+            imports.push(Import {dll_name: dll_name.clone(), name, ordinal, iat_slot: first_thunk + i * 4});
+        }
+        descriptor_rva += IMPORT_DESCRIPTOR_SIZE;
+    }
+    imports
+}
+
+/// The name a function is imported by: `_Name@8`, `@Name@8` and `_Name`
+/// are all `Name`. C++ names are imported as they are.
+fn undecorate(symbol_name: &str) -> &str {
+    if symbol_name.starts_with('?') {
+        return symbol_name;
+    }
+    let name = symbol_name.strip_prefix(['_', '@']).unwrap_or(symbol_name);
+    match name.find('@') {
+        Some(i) if i > 0 => &name[..i],
+        _ => name,
+    }
+}
+
+/// Defines the undefined symbols of `symbol_table` that name a function the
+/// image imports. `__imp_` symbols are defined at the IAT slot of the
+/// function. Other names get a thunk jumping through the slot, in a new
+/// ".text" section. Imports by ordinal are found by the names in
+/// `ordinal_imports`.
+pub fn resolve_imports(image: &mut Image, symbol_table: &mut Vec<SymbolTableRecord>, ordinal_imports: &[OrdinalImport]) -> ImportResolution {
+    let imports = read_imports(image);
+    for ordinal_import in ordinal_imports {
+        let is_imported = imports.iter().any(|i| i.ordinal == Some(ordinal_import.ordinal) && i.dll_name.eq_ignore_ascii_case(&ordinal_import.dll_name));
+        assert!(is_imported, "the image doesn't import ordinal {} of {}, recheck {}", ordinal_import.ordinal, ordinal_import.dll_name, ordinal_import.name);
+    }
+    let find_slot = |symbol_name: &str| {
+        [symbol_name, undecorate(symbol_name)].into_iter().find_map(|name| {
+            if let Some(import) = imports.iter().find(|i| i.name.as_deref() == Some(name)) {
+                return Some(import.iat_slot);
+            }
+            let ordinal_import = ordinal_imports.iter().find(|o| o.name == name)?;
+            imports.iter()
+                .find(|i| i.ordinal == Some(ordinal_import.ordinal) && i.dll_name.eq_ignore_ascii_case(&ordinal_import.dll_name))
+                .map(|i| i.iat_slot)
+        })
+    };
+
+    // Undefined names, with their type, that nothing defines.
+    let mut undefined: Vec<(String, u16)> = Vec::new();
+    for symbol in symbol_table.iter() {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) if s.section_number == 0 && s.storage_class == IMAGE_SYM_CLASS_EXTERNAL => s,
+            _ => continue,
+        };
+        let is_defined = symbol_table.iter().any(|other| matches!(other, SymbolTableRecord::Symbol(o) if o.name == s.name && o.section_number > 0));
+        if !is_defined && !undefined.contains(&(s.name.clone(), s.symbol_type)) {
+            undefined.push((s.name.clone(), s.symbol_type));
+        }
+    }
+
+    let mut resolution = ImportResolution {slots: 0, thunks: 0, absolute_sites: Vec::new()};
+    let mut thunks = Vec::new();
+    let mut thunk_symbols = Vec::new();
+    for (name, symbol_type) in undefined {
+        if let Some(import_name) = name.strip_prefix(IMPORT_POINTER_PREFIX) {
+            if let Some(slot) = find_slot(import_name) {
+                let si = image.section_index_for_rva(slot).unwrap();
+                let value = slot - image.sections[si].virtual_address;
+                symbol_table.push(create_symbol_for_table(name, value, i16::try_from(si + 1).unwrap(), symbol_type == 0x0020));
+                resolution.slots += 1;
+            }
+        } else if let Some(slot) = find_slot(&name) {
+            // A thunk is code, so a data reference would read the jmp instead of the import.
+            assert!(symbol_type == 0x0020, "{} is imported by the image but isn't referenced as a function, reference {}{} instead", name, IMPORT_POINTER_PREFIX, name);
+            thunk_symbols.push((name, u32::try_from(thunks.len()).unwrap()));
+            thunks.extend(THUNK_OPCODE);
+            thunks.extend(image.rva_to_va(slot).to_le_bytes());
+            thunks.resize(thunks.len().next_multiple_of(THUNK_SIZE), 0xcc);
+        }
+    }
+
+    if !thunks.is_empty() {
+        let si = image.append_section(Section {
+            name: ".text".to_string(),
+            raw_data: thunks,
+            characteristics: IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+            ..Default::default()
+        });
+        let virtual_address = image.sections[si].virtual_address;
+        for (name, offset) in thunk_symbols {
+            symbol_table.push(create_symbol_for_table(name, offset, i16::try_from(si + 1).unwrap(), true));
+            resolution.absolute_sites.push(virtual_address + offset + 2);
+            resolution.thunks += 1;
+        }
+    }
+    resolution
+}
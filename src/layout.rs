@@ -0,0 +1,230 @@
+use super::coff::{
+    COFF,
+    Section,
+    SymbolTableRecord,
+    IMAGE_SCN_CNT_CODE,
+    IMAGE_SCN_CNT_INITIALIZED_DATA,
+    IMAGE_SCN_CNT_UNINITIALIZED_DATA,
+    IMAGE_SCN_LNK_COMDAT,
+    IMAGE_SCN_ALIGN_MASK,
+    IMAGE_SCN_MEM_EXECUTE,
+    IMAGE_SCN_MEM_READ,
+    IMAGE_SCN_MEM_WRITE,
+};
+use super::pe::{Image, calculate_aligned_size};
+
+const OUTPUT_CHARACTERISTICS: u32 =
+    IMAGE_SCN_CNT_CODE
+    | IMAGE_SCN_CNT_INITIALIZED_DATA
+    | IMAGE_SCN_CNT_UNINITIALIZED_DATA
+    | IMAGE_SCN_MEM_EXECUTE
+    | IMAGE_SCN_MEM_READ
+    | IMAGE_SCN_MEM_WRITE;
+
+/// What goes in the gaps between section contributions and at the end of a
+/// section's raw data.
+#[derive(Clone, Copy)]
+pub enum Fill {
+    /// `int3`, so that stray jumps into padding trap.
+    Int3,
+    /// The recommended multi-byte `nop` forms, for padding that may be
+    /// executed.
+    Nop,
+    Byte(u8),
+}
+
+/// Parses a fill given as "int3", "nop" or a byte value such as "0x90".
+pub fn parse_fill(fill_str: &str) -> Fill {
+    match fill_str {
+        "int3" => Fill::Int3,
+        "nop" => Fill::Nop,
+        _ => {
+            let byte = match fill_str.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => fill_str.parse::<u8>(),
+            };
+            Fill::Byte(byte.unwrap_or_else(|_| panic!("invalid fill {}", fill_str)))
+        }
+    }
+}
+
+/// An output section being put together from object sections.
+struct OutputSection {
+    name: String,
+    characteristics: u32,
+    /// Indices of the object sections that make it up, in layout order.
+    members: Vec<usize>,
+}
+
+/// Merges the sections of `coff` into output sections and appends those to
+/// the image. Object sections are grouped the way the PE format groups them:
+/// ".text$a" and ".text$b" both end up in ".text", sorted by the part after
+/// the "$". Within a group, sections defining a symbol listed in `order`
+/// come first, in the order of the list. Symbols and relocations are moved
+/// along with their sections.
+///
+/// Padding in code sections is `int3` and zeros everywhere else, unless
+/// `fills` names the output section.
+pub fn append_sections(image: &mut Image, coff: &mut COFF, symbol_table_index_delta: u32, order: &[String], fills: &[(String, Fill)]) {
+    let file_alignment = image.optional_header.file_alignment;
+
+    let ranks = rank_sections(coff, order);
+    let mut output_sections: Vec<OutputSection> = Vec::new();
+    for (si, section) in coff.sections.iter().enumerate() {
+        let other_characteristics = section.characteristics & !OUTPUT_CHARACTERISTICS & !IMAGE_SCN_LNK_COMDAT & !IMAGE_SCN_ALIGN_MASK;
+        assert_eq!(other_characteristics, 0, "unsupported characteristics in section {}", section.name);
+
+        let name = group_name(&section.name);
+        let characteristics = section.characteristics & OUTPUT_CHARACTERISTICS;
+        match output_sections.iter_mut().find(|o| o.name == name && o.characteristics == characteristics) {
+            Some(o) => o.members.push(si),
+            None => output_sections.push(OutputSection {
+                name: name.to_string(),
+                characteristics,
+                members: vec![si],
+            }),
+        }
+    }
+    for output_section in &mut output_sections {
+        output_section.members.sort_by(|&a, &b| {
+            group_suffix(&coff.sections[a].name).cmp(group_suffix(&coff.sections[b].name))
+                .then(ranks[a].cmp(&ranks[b]))
+                .then(a.cmp(&b))
+        });
+    }
+
+    let first_output_section_number = i16::try_from(image.sections.len() + 1).unwrap();
+    // Output section number and offset of every object section.
+    let mut placements = vec![(0, 0); coff.sections.len()];
+    for (oi, output_section) in output_sections.into_iter().enumerate() {
+        let mut section = Section {
+            name: output_section.name,
+            characteristics: output_section.characteristics,
+            ..Default::default()
+        };
+        let fill = match fills.iter().find(|(name, _)| *name == section.name) {
+            Some((_, fill)) => *fill,
+            None if section.characteristics & IMAGE_SCN_CNT_CODE != 0 => Fill::Int3,
+            None => Fill::Byte(0),
+        };
+
+        for si in output_section.members {
+            let member = &mut coff.sections[si];
+
+            let offset = calculate_aligned_size(u32::try_from(section.raw_data.len()).unwrap(), section_alignment_of(member));
+            pad(&mut section.raw_data, usize::try_from(offset).unwrap(), fill);
+            section.raw_data.extend(&member.raw_data);
+
+            for mut relocation in std::mem::take(&mut member.relocations) {
+                relocation.virtual_address += offset;
+                relocation.symbol_table_index += symbol_table_index_delta;
+                section.relocations.push(relocation);
+            }
+
+            placements[si] = (first_output_section_number + i16::try_from(oi).unwrap(), offset);
+        }
+
+        let raw_data_length = u32::try_from(section.raw_data.len()).unwrap();
+        // The padding up to FileAlignment isn't part of the section.
+        section.virtual_size = raw_data_length;
+
+        let padded_length = calculate_aligned_size(raw_data_length, file_alignment);
+        pad(&mut section.raw_data, usize::try_from(padded_length).unwrap(), fill);
+
+        image.append_section(section);
+    }
+
+    for symbol in &mut coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number < 1 {
+            continue;
+        }
+        let (section_number, offset) = placements[usize::try_from(s.section_number - 1).unwrap()];
+        s.section_number = section_number;
+        s.value += offset;
+    }
+}
+
+/// Grows `data` to `length` bytes with `fill`.
+fn pad(data: &mut Vec<u8>, length: usize, fill: Fill) {
+    const NOPS: [&[u8]; 9] = [
+        &[0x90],
+        &[0x66, 0x90],
+        &[0x0f, 0x1f, 0x00],
+        &[0x0f, 0x1f, 0x40, 0x00],
+        &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+        &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+        &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+        &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    ];
+
+    match fill {
+        Fill::Int3 => data.resize(length, 0xcc),
+        Fill::Byte(b) => data.resize(length, b),
+        Fill::Nop => {
+            while data.len() < length {
+                let nop_length = (length - data.len()).min(NOPS.len());
+                data.extend(NOPS[nop_length - 1]);
+            }
+        }
+    }
+}
+
+/// Reads a list of symbol names, one per line. Empty lines and lines
+/// starting with "#" are skipped.
+pub fn read_order_file(order_file_str: &str) -> Vec<String> {
+    order_file_str.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Position in `order` of the first listed symbol each section defines, or
+/// `order.len()` for sections that define none.
+fn rank_sections(coff: &COFF, order: &[String]) -> Vec<usize> {
+    let mut ranks = vec![order.len(); coff.sections.len()];
+    for symbol in &coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number < 1 {
+            continue;
+        }
+        if let Some(rank) = order.iter().position(|name| *name == s.name) {
+            let si = usize::try_from(s.section_number - 1).unwrap();
+            ranks[si] = ranks[si].min(rank);
+        }
+    }
+    ranks
+}
+
+/// Name of the output section a section goes into: ".text$mn" and, the way
+/// MinGW names them, ".text.foo" both go into ".text".
+pub fn group_name(name: &str) -> &str {
+    let name = name.trim_end_matches('\0');
+    let end = match name.find('$') {
+        Some(i) => i,
+        None if name.starts_with('.') => name[1..].find('.').map_or(name.len(), |i| i + 1),
+        None => name.len(),
+    };
+    &name[..end]
+}
+
+pub fn group_suffix(name: &str) -> &str {
+    let name = name.trim_end_matches('\0');
+    &name[group_name(name).len()..]
+}
+
+fn section_alignment_of(section: &Section) -> u32 {
+    match (section.characteristics & IMAGE_SCN_ALIGN_MASK) >> 20 {
+        // No alignment given, the default is 16 bytes.
+        0 => 16,
+        n => 1 << (n - 1),
+    }
+}
@@ -0,0 +1,126 @@
+use super::coff::{Section, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ};
+use super::pe::{DirectoryEntry, Image};
+
+/// Offsets of the fields of IMAGE_LOAD_CONFIG_DIRECTORY32 irl uses.
+const SE_HANDLER_TABLE_OFFSET: u32 = 64;
+const SE_HANDLER_COUNT_OFFSET: u32 = 68;
+const GUARD_CF_FUNCTION_TABLE_OFFSET: u32 = 80;
+const GUARD_CF_FUNCTION_COUNT_OFFSET: u32 = 84;
+const GUARD_FLAGS_OFFSET: u32 = 88;
+
+const IMAGE_GUARD_CF_FUNCTION_TABLE_PRESENT: u32 = 0x00000400;
+const IMAGE_GUARD_CF_FUNCTION_TABLE_SIZE_MASK: u32 = 0xF0000000;
+const IMAGE_GUARD_CF_FUNCTION_TABLE_SIZE_SHIFT: u32 = 28;
+
+/// What to do about Control Flow Guard when linking into an image that has it.
+#[derive(Default, PartialEq)]
+pub enum Cfg {
+    /// Add the object's functions to the image's CFG function table.
+    #[default]
+    Register,
+    /// Clear GUARD_CF, turning off the checks for the whole image.
+    Clear,
+}
+
+/// The RVA of the load config directory and the size it gives itself, which
+/// tells which fields it has.
+fn load_config(image: &Image) -> Option<(u32, u32)> {
+    let directory = image.directory(DirectoryEntry::LoadConfig)?;
+    let size = u32::from_le_bytes(image.section_data(directory.virtual_address, 4)?.try_into().unwrap());
+    Some((directory.virtual_address, size))
+}
+
+/// Adds the RVAs in `functions` to GuardCFFunctionTable, so that indirect
+/// calls to them pass the CFG check. The table is rebuilt in a new ".gfids"
+/// section, sorted, with the per-entry flags of the existing entries kept
+/// and none set for the new ones. Returns the number of functions added and
+/// the RVA of the table pointer, which needs a base relocation, or nothing
+/// if the load config is too old to have a function table.
+pub fn register_cf_targets(image: &mut Image, functions: &[u32]) -> Option<(usize, u32)> {
+    let (load_config_rva, size) = load_config(image)?;
+    if size < GUARD_FLAGS_OFFSET + 4 {
+        return None;
+    }
+    let table_pointer_rva = load_config_rva + GUARD_CF_FUNCTION_TABLE_OFFSET;
+    let table_va = image.read_u32(table_pointer_rva);
+    let count = image.read_u32(load_config_rva + GUARD_CF_FUNCTION_COUNT_OFFSET);
+    let guard_flags = image.read_u32(load_config_rva + GUARD_FLAGS_OFFSET);
+    // Every entry is an RVA followed by as many bytes of flags as GuardFlags says.
+    let stride = 4 + ((guard_flags & IMAGE_GUARD_CF_FUNCTION_TABLE_SIZE_MASK) >> IMAGE_GUARD_CF_FUNCTION_TABLE_SIZE_SHIFT);
+
+    let mut entries = Vec::new();
+    if count != 0 {
+        let table_rva = image.va_to_rva(table_va).unwrap();
+        let table = image.section_data(table_rva, count * stride).expect("GuardCFFunctionTable isn't in initialized data");
+        for entry in table.chunks_exact(usize::try_from(stride).unwrap()) {
+            entries.push((u32::from_le_bytes(entry[..4].try_into().unwrap()), entry[4..].to_vec()));
+        }
+    }
+    let existing_count = entries.len();
+    let flags_length = usize::try_from(stride - 4).unwrap();
+    entries.extend(functions.iter().map(|&rva| (rva, vec![0; flags_length])));
+    // The stable sort keeps an existing entry ahead of a new one for the
+    // same function, so its flags survive.
+    entries.sort_by_key(|(rva, _)| *rva);
+    entries.dedup_by_key(|(rva, _)| *rva);
+    let added = entries.len() - existing_count;
+
+    let mut raw_data = Vec::with_capacity(entries.len() * usize::try_from(stride).unwrap());
+    for (rva, flags) in &entries {
+        raw_data.extend(rva.to_le_bytes());
+        raw_data.extend(flags);
+    }
+    let si = image.append_section(Section {
+        name: ".gfids".to_string(),
+        raw_data,
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+        ..Default::default()
+    });
+    let new_table_va = image.rva_to_va(image.sections[si].virtual_address);
+    image.write_u32(table_pointer_rva, new_table_va);
+    image.write_u32(load_config_rva + GUARD_CF_FUNCTION_COUNT_OFFSET, u32::try_from(entries.len()).unwrap());
+    image.write_u32(load_config_rva + GUARD_FLAGS_OFFSET, guard_flags | IMAGE_GUARD_CF_FUNCTION_TABLE_PRESENT);
+    Some((added, table_pointer_rva))
+}
+
+/// Adds the RVAs in `handlers` to SEHandlerTable, so that the exception
+/// dispatcher accepts them. The table is rebuilt, sorted, in a new ".sxdata"
+/// section. Returns the number of handlers added, or nothing if the image
+/// isn't linked with SafeSEH and takes any handler.
+pub fn register_se_handlers(image: &mut Image, handlers: &[u32]) -> Option<usize> {
+    let (load_config_rva, size) = load_config(image)?;
+    if size < SE_HANDLER_COUNT_OFFSET + 4 {
+        return None;
+    }
+    let table_pointer_rva = load_config_rva + SE_HANDLER_TABLE_OFFSET;
+    let table_va = image.read_u32(table_pointer_rva);
+    if table_va == 0 {
+        return None;
+    }
+    let count = image.read_u32(load_config_rva + SE_HANDLER_COUNT_OFFSET);
+
+    let table_rva = image.va_to_rva(table_va).unwrap();
+    let table = image.section_data(table_rva, count * 4).expect("SEHandlerTable isn't in initialized data");
+    let mut entries = table.chunks_exact(4)
+        .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+        .collect::<Vec<u32>>();
+    let existing_count = entries.len();
+    entries.extend(handlers);
+    entries.sort();
+    entries.dedup();
+    let added = entries.len() - existing_count;
+    if added == 0 {
+        return Some(0);
+    }
+
+    let si = image.append_section(Section {
+        name: ".sxdata".to_string(),
+        raw_data: entries.iter().flat_map(|rva| rva.to_le_bytes()).collect(),
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+        ..Default::default()
+    });
+    let new_table_va = image.rva_to_va(image.sections[si].virtual_address);
+    image.write_u32(table_pointer_rva, new_table_va);
+    image.write_u32(load_config_rva + SE_HANDLER_COUNT_OFFSET, u32::try_from(entries.len()).unwrap());
+    Some(added)
+}
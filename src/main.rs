@@ -1,23 +1,22 @@
-use std::io::{Cursor, Write, Seek, SeekFrom};
+use std::io::{Cursor, Write};
 
-use byteorder::{WriteBytesExt, LittleEndian};
-use coff::SymbolTableRecord;
+use coff::{SymbolTableRecord, IMAGE_SCN_LNK_INFO, IMAGE_SCN_LNK_REMOVE};
 
 mod pe;
+mod dos;
 mod coff;
 mod image_info;
-
-const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
-const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x00000040;
-const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x00000080;
-const IMAGE_SCN_LNK_COMDAT: u32 = 0x00001000;
-const IMAGE_SCN_ALIGN_2BYTES: u32 = 0x00200000;
-const IMAGE_SCN_ALIGN_4BYTES: u32 = 0x00300000;
-const IMAGE_SCN_ALIGN_16BYTES: u32 = 0x00500000;
-const IMAGE_SCN_ALIGN_32BYTES: u32 = 0x00600000;
-const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
-const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
-const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+mod gc;
+mod icf;
+mod layout;
+mod merge;
+mod options;
+mod reloc;
+mod load_config;
+mod tls;
+mod exports;
+mod imports;
+mod resources;
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
@@ -26,107 +25,291 @@ fn main() {
     let image_info_string = std::fs::read_to_string(&args[2]).unwrap();
     let coff_bytes = std::fs::read(&args[3]).unwrap();
     let out_path_string = &args[4];
+    let options = options::parse_options(&args[5..]);
 
     let mut pe = pe::read_image(Cursor::new(pe_bytes));
+    if let (Some(rich_header), Some(checksum)) = (&pe.stub.rich_header, pe.stub.rich_checksum()) {
+        if rich_header.checksum != checksum {
+            eprintln!("warning: the Rich header checksum is {:#010x}, but should be {:#010x}", rich_header.checksum, checksum);
+        }
+    }
     let mut symbol_table = Vec::new();
     image_info::fill_image_and_symbol_table_with_image_info(&mut pe, &mut symbol_table, &image_info_string);
+    let header_overrides = options.header.or(image_info::read_header_overrides(&image_info_string));
+    let image_info_exports = image_info::read_exports(&image_info_string);
+
+    // Resources are read before anything moves them.
+    let edited_resources = options.resources.as_ref().map(|path| {
+        let mut resources = resources::read_resources(&pe);
+        let base_directory = std::path::Path::new(path).parent().unwrap();
+        resources::edit_resources(&mut resources, &std::fs::read_to_string(path).unwrap(), base_directory);
+        resources
+    });
+    // Rewritten resources go last anyway, so the old ones can make room.
+    let resource_section_index = pe.directory(pe::DirectoryEntry::Resource).and_then(|d| pe.section_index_for_rva(d.virtual_address));
+    let resource_section = if options.rsrc_last || edited_resources.is_some() { resources::take_last_resource_section(&mut pe) } else { None };
+    if resource_section.is_some() {
+        // The sections from the resource section on move.
+        let moved_section_number = i16::try_from(resource_section_index.unwrap() + 1).unwrap();
+        let is_referenced = symbol_table.iter().any(|symbol| matches!(symbol, SymbolTableRecord::Symbol(s) if s.section_number >= moved_section_number));
+        assert!(!is_referenced, "the resource section moves, but image_info has symbols in or after it");
+    } else if options.rsrc_last && resource_section_index.is_some() {
+        eprintln!("warning: --rsrc-last: the resource section is followed by sections other than .reloc, leaving it in place");
+    }
 
-    {
-        let section_count = i16::try_from(pe.sections.len()).unwrap();
+    let object_symbols_start = symbol_table.len();
+    let first_object_section = pe.sections.len();
+    let (handler_indices, is_safe_seh_compatible, comp_id, mut exports, template_offsets) = {
         let mut coff = coff::read_coff(Cursor::new(coff_bytes));
-        for symbol in &mut coff.symbols {
-            match symbol {
-                coff::SymbolTableRecord::Symbol(s) => move_symbol(s, section_count),
-                coff::SymbolTableRecord::Aux(_) => (),
-            }
+        let handler_indices = coff::read_safe_seh_handlers(&coff);
+        let is_safe_seh_compatible = coff::is_safe_seh_compatible(&coff);
+        let comp_id = coff::read_comp_id(&coff);
+        let exports = coff::read_directives(&coff).exports;
+        if options.gc_sections {
+            let roots = collect_roots(&coff, &symbol_table, &image_info_exports, &options.tls_callbacks);
+            let collection = gc::collect_garbage(&mut coff, &roots);
+            println!("gc-sections: removed {} bytes in {} sections", collection.bytes, collection.sections);
+        }
+        if options.icf {
+            let folding = icf::fold_identical_sections(&mut coff);
+            println!("icf: folded {} bytes in {} sections", folding.bytes, folding.sections);
         }
-        append_sections(&mut pe, &mut coff, u32::try_from(symbol_table.len()).unwrap());
+        if options.merge_strings {
+            let merging = merge::merge_constants(&mut coff);
+            println!("merge-strings: saved {} bytes from {} sections", merging.bytes, merging.sections);
+        }
+        let template_offsets = if tls::has_tls_sections(&coff) { tls::prepend_template(&pe, &mut coff) } else { Vec::new() };
+        // Directives and .sxdata are for the linker, not the image.
+        let keep = coff.sections.iter()
+            .map(|s| !s.raw_data.is_empty() && s.characteristics & (IMAGE_SCN_LNK_INFO | IMAGE_SCN_LNK_REMOVE) == 0)
+            .collect::<Vec<bool>>();
+        coff::remove_sections(&mut coff, &keep);
+
+        let order = match &options.order_file {
+            Some(path) => layout::read_order_file(&std::fs::read_to_string(path).unwrap()),
+            None => Vec::new(),
+        };
+        layout::append_sections(&mut pe, &mut coff, u32::try_from(object_symbols_start).unwrap(), &order, &options.fills);
         symbol_table.extend(coff.symbols);
+        (handler_indices, is_safe_seh_compatible, comp_id, exports, template_offsets)
+    };
+    // Last, so that the object can replace what the image exports.
+    let export_symbols = exports::add_export_symbols(&pe, &mut symbol_table);
+    if export_symbols != 0 {
+        println!("exports: defined {} symbols from the image's exports", export_symbols);
+    }
+
+    let ordinal_imports = image_info::read_ordinal_imports(&image_info_string);
+    let import_resolution = imports::resolve_imports(&mut pe, &mut symbol_table, &ordinal_imports);
+    if import_resolution.slots != 0 || import_resolution.thunks != 0 {
+        println!("imports: bound {} symbols to IAT slots, added {} thunks", import_resolution.slots, import_resolution.thunks);
+    }
+
+    let functions = object_functions(&pe, &symbol_table[object_symbols_start..], first_object_section);
+    let handlers = handler_rvas(&pe, &symbol_table[object_symbols_start..], &handler_indices);
+
+    let template_section = (first_object_section..pe.sections.len()).find(|&si| pe.sections[si].name == tls::TLS_SECTION_NAME);
+    let callbacks = options.tls_callbacks.iter()
+        .map(|name| symbol_rva(&pe, &symbol_table, name).unwrap_or_else(|| panic!("could not find TLS callback {}", name)))
+        .collect::<Vec<u32>>();
+    let uses_tls_index = symbol_table.iter().any(|symbol| matches!(symbol, SymbolTableRecord::Symbol(s) if s.name == "__tls_index"));
+    // The image's template moved, along with the addresses in it.
+    let mut tls_sites = tls::template_sites(&pe, &symbol_table[object_symbols_start..], &template_offsets);
+    if template_section.is_some() || !callbacks.is_empty() || uses_tls_index {
+        tls_sites.extend(tls::update_tls_directory(&mut pe, &mut symbol_table, template_section, &callbacks));
+        if !callbacks.is_empty() {
+            println!("tls: added {} callbacks", callbacks.len());
+        }
+    }
+
+    exports.extend(image_info_exports);
+    if !exports.is_empty() {
+        let dll_name = std::path::Path::new(out_path_string).file_name().unwrap().to_string_lossy().into_owned();
+        let mut table = exports::read_export_table(&pe).unwrap_or_else(|| exports::ExportTable::new(dll_name));
+        for export in &exports {
+            // GCC leaves the C decoration out of its "-export:" directives.
+            let (symbol_name, rva) = [export.symbol.clone(), format!("_{}", export.symbol)].into_iter()
+                .find_map(|name| symbol_rva(&pe, &symbol_table, &name).map(|rva| (name, rva)))
+                .unwrap_or_else(|| panic!("could not find the symbol {} to export", export.symbol));
+            // Without an internal name, the export is named after the symbol.
+            let name = if export.name == export.symbol { exports::undecorate(&symbol_name) } else { export.name.as_str() };
+            table.add(if export.no_name { None } else { Some(name) }, rva, export.ordinal);
+        }
+        let si = exports::write_export_table(&mut pe, &mut table);
+        println!("exports: added {} exports, the export table is at {:#x}", exports.len(), pe.sections[si].virtual_address);
     }
 
-    fix_relocations(&mut pe, symbol_table);
+    let mut absolute_sites = fix_relocations(&mut pe, symbol_table);
+    absolute_sites.extend(tls_sites);
+    absolute_sites.extend(import_resolution.absolute_sites);
+
+    if pe.optional_header.dll_characteristics & pe::IMAGE_DLLCHARACTERISTICS_GUARD_CF != 0 {
+        let registration = match options.cfg {
+            load_config::Cfg::Register => load_config::register_cf_targets(&mut pe, &functions),
+            load_config::Cfg::Clear => None,
+        };
+        match registration {
+            Some((added, table_pointer_rva)) => {
+                println!("cfg: registered {} functions", added);
+                absolute_sites.push(table_pointer_rva);
+            }
+            None => {
+                pe.optional_header.dll_characteristics &= !pe::IMAGE_DLLCHARACTERISTICS_GUARD_CF;
+                match options.cfg {
+                    load_config::Cfg::Register => eprintln!("warning: cleared GUARD_CF, the load config has no function table to register the object's functions in"),
+                    load_config::Cfg::Clear => eprintln!("warning: cleared GUARD_CF, indirect calls aren't checked any more"),
+                }
+            }
+        }
+    }
+
+    if options.aslr == reloc::Aslr::Disable && pe.optional_header.dll_characteristics & pe::IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE != 0 {
+        pe.optional_header.dll_characteristics &= !pe::IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE;
+        eprintln!("warning: cleared DYNAMIC_BASE, the image always loads at {:#x}", pe.optional_header.image_base);
+    }
+    match load_config::register_se_handlers(&mut pe, &handlers) {
+        Some(added) => {
+            println!("safeseh: registered {} handlers", added);
+            if !is_safe_seh_compatible {
+                eprintln!("warning: the object isn't marked SafeSEH-compatible by @feat.00, handlers missing from its .sxdata will be rejected");
+            }
+        }
+        // An image without any handlers may not use any, whatever the object brings.
+        None if !handlers.is_empty() && pe.optional_header.dll_characteristics & pe::IMAGE_DLLCHARACTERISTICS_NO_SEH != 0 => {
+            pe.optional_header.dll_characteristics &= !pe::IMAGE_DLLCHARACTERISTICS_NO_SEH;
+            eprintln!("warning: cleared NO_SEH, the object has exception handlers");
+        }
+        None => (),
+    }
+
+    // DLLs are moved whenever their base is taken, ASLR or not, so base
+    // relocations are kept complete for any image that has them.
+    if reloc::is_relocatable(&pe) {
+        let added = reloc::add_base_relocations(&mut pe, &absolute_sites);
+        println!("base relocations: added {}", added);
+    } else if !absolute_sites.is_empty() && pe.optional_header.dll_characteristics & pe::IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE != 0 {
+        pe.optional_header.dll_characteristics &= !pe::IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE;
+        eprintln!("warning: cleared DYNAMIC_BASE, the image has no base relocations for the object's absolute addresses");
+    }
+
+    match (edited_resources, resource_section) {
+        (Some(resources), _) => {
+            let si = resources::write_resources(&mut pe, &resources);
+            println!("rsrc: wrote {} resources to a new resource section at {:#x}", resources.len(), pe.sections[si].virtual_address);
+        }
+        (None, Some(section)) => {
+            let si = resources::append_resource_section(&mut pe, section);
+            println!("rsrc: moved the resource section to {:#x}", pe.sections[si].virtual_address);
+        }
+        (None, None) => (),
+    }
+
+    if pe.has_signature() {
+        if options.keep_signature {
+            eprintln!("warning: keeping the Authenticode signature, it won't match the linked image");
+        } else {
+            pe.strip_signature();
+            eprintln!("warning: stripped the Authenticode signature, it wouldn't match the linked image");
+        }
+    }
+
+    match options.rich {
+        dos::Rich::Keep => (),
+        dos::Rich::Strip => {
+            if pe.stub.rich_header.take().is_some() {
+                println!("rich: stripped the Rich header");
+            }
+        }
+        dos::Rich::Recompute => pe.stub.recompute_rich_header(comp_id),
+    }
+
+    let growth = pe.grow_headers();
+    if growth.stub_bytes_reused != 0 {
+        println!("headers: reused {} bytes of the DOS stub for the section table", growth.stub_bytes_reused);
+    }
+    if growth.raw_data_shift != 0 {
+        println!("headers: moved the raw data of every section {:#x} bytes forward for the section table", growth.raw_data_shift);
+    }
+    pe.finalize();
+    pe.override_header(&header_overrides);
+
+    let checksum = options.checksum.unwrap_or(pe.optional_header.check_sum != 0);
+    if !checksum {
+        pe.optional_header.check_sum = 0;
+    }
 
     let mut buffer = Vec::new();
     let mut cursor = Cursor::new(&mut buffer);
     pe::write_image(pe, &mut cursor);
+    if checksum {
+        pe::write_checksum(&mut buffer);
+    }
     std::fs::File::create(out_path_string).unwrap().write_all(&buffer).unwrap();
 }
 
-fn move_symbol(s: &mut coff::Symbol, offset: i16) {
-    if s.section_number < 1 {
-        return;
+/// Garbage collection roots: everything the image refers to through
+/// image_info relocations, the exports image_info lists, the TLS callbacks,
+/// plus the object's `/EXPORT` and `/INCLUDE` symbols.
+fn collect_roots(coff: &coff::COFF, symbol_table: &[SymbolTableRecord], image_info_exports: &[coff::Export], tls_callbacks: &[String]) -> Vec<String> {
+    let mut roots = Vec::new();
+    for symbol in symbol_table {
+        match symbol {
+            SymbolTableRecord::Symbol(s) if s.section_number == 0 => roots.push(s.name.clone()),
+            _ => (),
+        }
+    }
+
+    let directives = coff::read_directives(coff);
+    for export in directives.exports.iter().chain(image_info_exports) {
+        // Export names may or may not carry the C decoration.
+        roots.push(format!("_{}", export.symbol));
+        roots.push(export.symbol.clone());
     }
-    s.section_number += offset;
+    roots.extend(directives.includes);
+    roots.extend(tls_callbacks.iter().cloned());
+    roots
 }
 
-fn append_sections(image: &mut pe::Image, coff: &mut coff::COFF, symbol_table_index_delta: u32) {
-    let section_alignment = image.optional_header.section_alignment;
-    let file_alignment = image.optional_header.file_alignment;
-    for si in 0..coff.sections.len() {
-        let section = &mut coff.sections[si];
-
-        let raw_data_length = u32::try_from(section.raw_data.len()).unwrap();
-        if raw_data_length == 0 {
-            continue;
-        }
-
-        section.virtual_size = calculate_aligned_size(raw_data_length, section_alignment);
-        assert_eq!(section.virtual_size % section_alignment, 0);
-
-        let last_image_section = &image.sections[image.sections.len() - 1];
-        let last_image_section_virtual_size = calculate_aligned_size(last_image_section.virtual_size, section_alignment);
-        section.virtual_address = last_image_section.virtual_address + last_image_section_virtual_size;
-        assert_eq!(section.virtual_address % section_alignment, 0);
-
-        let raw_data_alignment_difference = raw_data_length % file_alignment;
-        if raw_data_alignment_difference != 0 {
-            // TODO: evaluate other bytes?
-            let padding = vec![0xcc; usize::try_from(file_alignment - raw_data_alignment_difference).unwrap()];
-            section.raw_data.extend(padding);
-        }
-
-        for ri in 0..section.relocations.len() {
-            let relocation = &mut section.relocations[ri];
-            relocation.symbol_table_index += symbol_table_index_delta;
-        }
-
-        let alignment_characteristics =
-            section.characteristics
-            & !IMAGE_SCN_CNT_CODE
-            & !IMAGE_SCN_CNT_INITIALIZED_DATA
-            & !IMAGE_SCN_CNT_UNINITIALIZED_DATA
-            & !IMAGE_SCN_MEM_EXECUTE
-            & !IMAGE_SCN_MEM_READ
-            & !IMAGE_SCN_MEM_WRITE;
-        assert!(alignment_characteristics == (IMAGE_SCN_LNK_COMDAT | IMAGE_SCN_ALIGN_4BYTES)
-                || alignment_characteristics == IMAGE_SCN_ALIGN_2BYTES
-                || alignment_characteristics == IMAGE_SCN_ALIGN_4BYTES
-                || alignment_characteristics == IMAGE_SCN_ALIGN_16BYTES
-                || alignment_characteristics == IMAGE_SCN_ALIGN_32BYTES);
-
-        section.characteristics =
-            section.characteristics
-            & (IMAGE_SCN_CNT_CODE
-               | IMAGE_SCN_CNT_INITIALIZED_DATA
-               | IMAGE_SCN_CNT_UNINITIALIZED_DATA
-               | IMAGE_SCN_MEM_EXECUTE
-               | IMAGE_SCN_MEM_READ
-               | IMAGE_SCN_MEM_WRITE);
-
-        // TODO: This should probably be calculated.
-        image.coff_header.number_of_sections += 1;
-        image.optional_header.size_of_image += section.virtual_size;
-
-        image.sections.push(std::mem::take(section));
+/// RVAs of the functions the object defines, which may be called through a
+/// pointer. Symbols in the image's own sections, such as those of its
+/// exports, are left out.
+fn object_functions(image: &pe::Image, object_symbols: &[SymbolTableRecord], first_object_section: usize) -> Vec<u32> {
+    let mut functions = Vec::new();
+    for symbol in object_symbols {
+        match symbol {
+            SymbolTableRecord::Symbol(s) if s.section_number > 0 && usize::try_from(s.section_number - 1).unwrap() >= first_object_section && s.symbol_type == 0x0020 => {
+                functions.push(image.sections[usize::try_from(s.section_number - 1).unwrap()].virtual_address + s.value);
+            }
+            _ => (),
+        }
     }
+    functions
+}
+
+/// The RVA of the symbol called `name`, if something defines it.
+fn symbol_rva(image: &pe::Image, symbol_table: &[SymbolTableRecord], name: &str) -> Option<u32> {
+    symbol_table.iter().find_map(|symbol| match symbol {
+        SymbolTableRecord::Symbol(s) if s.section_number > 0 && s.name == name => {
+            Some(image.sections[usize::try_from(s.section_number - 1).unwrap()].virtual_address + s.value)
+        }
+        _ => None,
+    })
 }
 
-fn calculate_aligned_size(size: u32, alignment: u32) -> u32 {
-    let alignment_difference = size % alignment;
-    if alignment_difference != 0 {
-        return size + alignment - alignment_difference;
+/// RVAs of the exception handlers the object lists in `.sxdata`. Handlers
+/// whose sections were dropped are left out.
+fn handler_rvas(image: &pe::Image, object_symbols: &[SymbolTableRecord], handler_indices: &[u32]) -> Vec<u32> {
+    let mut handlers = Vec::new();
+    for &index in handler_indices {
+        match &object_symbols[usize::try_from(index).unwrap()] {
+            SymbolTableRecord::Symbol(s) if s.section_number > 0 => {
+                handlers.push(image.sections[usize::try_from(s.section_number - 1).unwrap()].virtual_address + s.value);
+            }
+            SymbolTableRecord::Symbol(_) => (),
+            SymbolTableRecord::Aux(_) => panic!(".sxdata refers to an aux symbol"),
+        }
     }
-    return size;
+    handlers
 }
 
 #[derive(Debug)]
@@ -142,25 +325,35 @@ struct RelocationPatch {
 enum RelocationType {
     Dir32,
     Dir32NB,
+    SecRel,
     Rel32,
 }
 
-fn fix_relocations(image: &mut pe::Image, symbol_table: Vec<SymbolTableRecord>) {
+/// Applies the relocations of every section and returns the RVAs of the
+/// absolute addresses written, which need base relocations.
+fn fix_relocations(image: &mut pe::Image, symbol_table: Vec<SymbolTableRecord>) -> Vec<u32> {
     let mut patches = Vec::new();
-    let mut si = 0;
-    for section in &mut image.sections {
+    for (si, section) in image.sections.iter_mut().enumerate() {
         for relocation in &mut section.relocations {
             let relocation_type = match relocation.relocation_type {
                 0x0006 => RelocationType::Dir32,
                 0x0007 => RelocationType::Dir32NB,
+                0x000B => RelocationType::SecRel,
                 0x0014 => RelocationType::Rel32,
                 n => panic!("unknown relocation type {:#06x}", n),
             };
-            let undefined_symbol = match &symbol_table[usize::try_from(relocation.symbol_table_index).unwrap()] {
+            let symbol = match &symbol_table[usize::try_from(relocation.symbol_table_index).unwrap()] {
                 SymbolTableRecord::Symbol(s) => s,
                 SymbolTableRecord::Aux(_) => panic!("tried to look up aux symbol"),
             };
-            let defined_symbol = find_defined_symbol(undefined_symbol, &symbol_table);
+            // Symbols defined by the object are used as-is, names are only
+            // looked up for the undefined ones. Static names such as those of
+            // COMDAT sections aren't unique.
+            let defined_symbol = if symbol.section_number > 0 {
+                symbol
+            } else {
+                find_defined_symbol(symbol, &symbol_table)
+            };
             patches.push(RelocationPatch {
                 symbol_section_number: defined_symbol.section_number,
                 symbol_value: defined_symbol.value,
@@ -169,40 +362,34 @@ fn fix_relocations(image: &mut pe::Image, symbol_table: Vec<SymbolTableRecord>)
                 relocation_type,
             });
         }
-        si += 1;
     }
 
+    let mut absolute_sites = Vec::new();
     for patch in patches {
-        match patch.relocation_type {
+        let symbol_rva = image.sections[usize::try_from(patch.symbol_section_number - 1).unwrap()].virtual_address + patch.symbol_value;
+        let relocation_rva = image.sections[patch.relocation_section_index].virtual_address + patch.relocation_position;
+        let value = match patch.relocation_type {
             RelocationType::Dir32 => {
-                let va = image.optional_header.image_base + image.sections[usize::try_from(patch.symbol_section_number - 1).unwrap()].virtual_address + patch.symbol_value;
-
-                let mut raw_data_cursor = Cursor::new(&mut image.sections[patch.relocation_section_index].raw_data);
-                raw_data_cursor.seek(SeekFrom::Start(u64::from(patch.relocation_position))).unwrap();
-                raw_data_cursor.write_u32::<LittleEndian>(va).unwrap();
+                absolute_sites.push(relocation_rva);
+                image.rva_to_va(symbol_rva)
             }
-            RelocationType::Dir32NB => {
-                let rva = image.sections[usize::try_from(patch.symbol_section_number - 1).unwrap()].virtual_address + patch.symbol_value;
+            RelocationType::Dir32NB => symbol_rva,
+            // Relative to the section, which for TLS variables makes it the
+            // offset into the template.
+            RelocationType::SecRel => patch.symbol_value,
+            RelocationType::Rel32 => symbol_rva.wrapping_sub(relocation_rva + 4),
+        };
 
-                let mut raw_data_cursor = Cursor::new(&mut image.sections[patch.relocation_section_index].raw_data);
-                raw_data_cursor.seek(SeekFrom::Start(u64::from(patch.relocation_position))).unwrap();
-                raw_data_cursor.write_u32::<LittleEndian>(rva).unwrap();
-            }
-            RelocationType::Rel32 => {
-                let symbol_section_virtual_address = image.sections[usize::try_from(patch.symbol_section_number - 1).unwrap()].virtual_address;
-                let relocation_section_virtual_address = image.sections[patch.relocation_section_index].virtual_address;
-                let symbol_virtual_address = image.optional_header.image_base + symbol_section_virtual_address + patch.symbol_value;
-                let displacement = symbol_virtual_address.overflowing_sub(image.optional_header.image_base + relocation_section_virtual_address + patch.relocation_position + 4).0;
-
-                let mut raw_data_cursor = Cursor::new(&mut image.sections[patch.relocation_section_index].raw_data);
-                raw_data_cursor.seek(SeekFrom::Start(u64::from(patch.relocation_position))).unwrap();
-                raw_data_cursor.write_u32::<LittleEndian>(displacement).unwrap();
-            }
-        }
+        let field = image.section_data_mut(relocation_rva, 4)
+            .unwrap_or_else(|| panic!("relocation at RVA {:#010x} isn't in initialized data", relocation_rva));
+        // Object files keep the addend in the field being patched.
+        let addend = u32::from_le_bytes((&*field).try_into().unwrap());
+        field.copy_from_slice(&value.wrapping_add(addend).to_le_bytes());
     }
+    absolute_sites
 }
 
-fn find_defined_symbol<'a>(undefined_symbol: &'a coff::Symbol, symbol_table: &'a Vec<SymbolTableRecord>) -> &'a coff::Symbol {
+fn find_defined_symbol<'a>(undefined_symbol: &'a coff::Symbol, symbol_table: &'a [SymbolTableRecord]) -> &'a coff::Symbol {
     for symbol in symbol_table {
         let s = match symbol {
             SymbolTableRecord::Symbol(s) => s,
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+
+use super::coff::{
+    COFF,
+    Section,
+    SymbolTableRecord,
+    IMAGE_SCN_CNT_CODE,
+    IMAGE_SCN_CNT_INITIALIZED_DATA,
+    IMAGE_SCN_LNK_COMDAT,
+    IMAGE_SCN_ALIGN_MASK,
+    IMAGE_SCN_MEM_READ,
+    IMAGE_SCN_MEM_WRITE,
+    IMAGE_SYM_CLASS_EXTERNAL,
+    remove_sections,
+};
+
+/// Prefixes of MSVC's COMDAT constants that are only ever compared by value.
+const CONSTANT_PREFIXES: [&str; 5] = ["??_C@_1", "__real@", "__xmm@", "__ymm@", "__mask@"];
+
+pub struct Merging {
+    pub sections: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// NUL-terminated narrow strings, which may share a tail.
+    Strings,
+    /// Data that may only be merged with an identical copy.
+    Constant,
+}
+
+/// A string or constant found in one of the mergeable sections.
+struct Piece {
+    offset: u32,
+    length: u32,
+    alignment: u32,
+    merged_offset: u32,
+}
+
+/// Merges the strings and constants of the read-only sections that only hold
+/// such things (MinGW's ".rdata$zzz", MSVC's "??_C@" string literals and
+/// "__real@"-style constants) into a single ".rdata" section. Identical copies
+/// are kept once and a string that is the tail of another points into it,
+/// as long as that keeps it aligned. Symbols and relocations pointing into
+/// the merged sections are rewritten to point at the copy that's kept.
+pub fn merge_constants(coff: &mut COFF) -> Merging {
+    let kinds = classify_sections(coff);
+    let candidate_count = kinds.iter().filter(|k| k.is_some()).count();
+    if candidate_count == 0 {
+        return Merging {sections: 0, bytes: 0};
+    }
+    let mut pieces = Vec::with_capacity(kinds.len());
+    for (si, kind) in kinds.iter().enumerate() {
+        pieces.push(match kind {
+            Some(kind) => split_section(&coff.sections[si], *kind),
+            None => Vec::new(),
+        });
+    }
+
+    let mut merged_data = Vec::new();
+    let mut merged_alignment = 1;
+
+    // Sorting the reversed strings in descending order puts every string
+    // right after the longest string it's a tail of.
+    let mut strings: HashMap<&[u8], u32> = HashMap::new();
+    for (si, kind) in kinds.iter().enumerate() {
+        if *kind != Some(Kind::Strings) {
+            continue;
+        }
+        for piece in &pieces[si] {
+            let alignment = strings.entry(piece_data(&coff.sections[si], piece)).or_insert(1);
+            *alignment = (*alignment).max(piece.alignment);
+        }
+    }
+    let mut sorted_strings = strings.keys().copied().collect::<Vec<&[u8]>>();
+    sorted_strings.sort_by(|a, b| b.iter().rev().cmp(a.iter().rev()));
+    let mut string_offsets = HashMap::new();
+    let mut previous: Option<(&[u8], u32)> = None;
+    for string in sorted_strings {
+        let alignment = strings[string];
+        merged_alignment = merged_alignment.max(alignment);
+        if let Some((previous_string, previous_offset)) = previous {
+            let offset = previous_offset + u32::try_from(previous_string.len() - string.len()).unwrap();
+            if previous_string.ends_with(string) && offset % alignment == 0 {
+                string_offsets.insert(string, offset);
+                continue;
+            }
+        }
+        let offset = append_aligned(&mut merged_data, string, alignment);
+        string_offsets.insert(string, offset);
+        previous = Some((string, offset));
+    }
+
+    let mut constants: Vec<(&[u8], u32)> = Vec::new();
+    for (si, kind) in kinds.iter().enumerate() {
+        if *kind != Some(Kind::Constant) {
+            continue;
+        }
+        let piece = &pieces[si][0];
+        let data = piece_data(&coff.sections[si], piece);
+        match constants.iter_mut().find(|(d, _)| *d == data) {
+            Some((_, alignment)) => *alignment = (*alignment).max(piece.alignment),
+            None => constants.push((data, piece.alignment)),
+        }
+    }
+    let mut constant_offsets = HashMap::new();
+    for (data, alignment) in constants {
+        merged_alignment = merged_alignment.max(alignment);
+        constant_offsets.insert(data, append_aligned(&mut merged_data, data, alignment));
+    }
+
+    let mut merged_offsets = Vec::with_capacity(kinds.len());
+    for (si, kind) in kinds.iter().enumerate() {
+        let section = &coff.sections[si];
+        merged_offsets.push(pieces[si].iter().map(|piece| {
+            let data = piece_data(section, piece);
+            match kind {
+                Some(Kind::Strings) => string_offsets[data],
+                _ => constant_offsets[data],
+            }
+        }).collect::<Vec<u32>>());
+    }
+    for (section_pieces, section_merged_offsets) in pieces.iter_mut().zip(merged_offsets) {
+        for (piece, merged_offset) in section_pieces.iter_mut().zip(section_merged_offsets) {
+            piece.merged_offset = merged_offset;
+        }
+    }
+
+    let candidate_bytes = kinds.iter().enumerate()
+        .filter(|(_, k)| k.is_some())
+        .map(|(si, _)| coff.sections[si].raw_data.len())
+        .sum::<usize>();
+
+    let merged_section_number = i16::try_from(coff.sections.len() + 1).unwrap();
+    let merged_data_length = merged_data.len();
+    coff.sections.push(Section {
+        name: ".rdata".to_string(),
+        raw_data: merged_data,
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | alignment_characteristics(merged_alignment),
+        ..Default::default()
+    });
+
+    // Relocations carry their addend in the field they patch, and it can move
+    // them into a different piece than their symbol, so they're rewritten
+    // before the symbols.
+    for si in 0..coff.sections.len() {
+        for ri in 0..coff.sections[si].relocations.len() {
+            let relocation = &coff.sections[si].relocations[ri];
+            if !matches!(relocation.relocation_type, 0x0006 | 0x0007 | 0x000B | 0x0014) {
+                continue;
+            }
+            let position = usize::try_from(relocation.virtual_address).unwrap();
+            let symbol = match &coff.symbols[usize::try_from(relocation.symbol_table_index).unwrap()] {
+                SymbolTableRecord::Symbol(s) if s.section_number > 0 => s,
+                _ => continue,
+            };
+            let target_si = usize::try_from(symbol.section_number - 1).unwrap();
+            if kinds.get(target_si).copied().flatten().is_none() {
+                continue;
+            }
+            let field = &mut coff.sections[si].raw_data[position..position + 4];
+            let addend = u32::from_le_bytes(field.try_into().unwrap());
+            let target = map_offset(&pieces[target_si], symbol.value.wrapping_add(addend));
+            let value = map_offset(&pieces[target_si], symbol.value);
+            field.copy_from_slice(&target.wrapping_sub(value).to_le_bytes());
+        }
+    }
+
+    for symbol in &mut coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) if s.section_number > 0 => s,
+            _ => continue,
+        };
+        let si = usize::try_from(s.section_number - 1).unwrap();
+        if kinds.get(si).copied().flatten().is_none() {
+            continue;
+        }
+        s.value = map_offset(&pieces[si], s.value);
+        s.section_number = merged_section_number;
+    }
+
+    let mut keep = kinds.iter().map(|k| k.is_none()).collect::<Vec<bool>>();
+    keep.push(true);
+    remove_sections(coff, &keep);
+
+    Merging {
+        sections: candidate_count,
+        // Alignment padding can make the merged data the larger.
+        bytes: candidate_bytes.saturating_sub(merged_data_length),
+    }
+}
+
+fn classify_sections(coff: &COFF) -> Vec<Option<Kind>> {
+    let mut kinds = Vec::with_capacity(coff.sections.len());
+    for section in &coff.sections {
+        let is_read_only_data = section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0
+            && section.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_WRITE) == 0;
+        if !is_read_only_data || section.raw_data.is_empty() || !section.relocations.is_empty() {
+            kinds.push(None);
+        } else if section.name.trim_end_matches('\0') == ".rdata$zzz" && section.raw_data.ends_with(&[0]) {
+            kinds.push(Some(Kind::Strings));
+        } else {
+            kinds.push(None);
+        }
+    }
+
+    for symbol in &coff.symbols {
+        let s = match symbol {
+            SymbolTableRecord::Symbol(s) => s,
+            SymbolTableRecord::Aux(_) => continue,
+        };
+        if s.section_number < 1 || s.storage_class != IMAGE_SYM_CLASS_EXTERNAL || s.value != 0 {
+            continue;
+        }
+        let si = usize::try_from(s.section_number - 1).unwrap();
+        let section = &coff.sections[si];
+        let is_read_only_comdat = section.characteristics & IMAGE_SCN_LNK_COMDAT != 0
+            && section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0
+            && section.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_WRITE) == 0;
+        if !is_read_only_comdat || section.raw_data.is_empty() || !section.relocations.is_empty() {
+            continue;
+        }
+        if CONSTANT_PREFIXES.iter().any(|p| s.name.starts_with(p)) {
+            kinds[si] = Some(Kind::Constant);
+        } else if s.name.starts_with("??_C@_0") {
+            // Only a string literal that is exactly one string may share its tail.
+            let nul_count = section.raw_data.iter().filter(|&&b| b == 0).count();
+            let is_one_string = section.raw_data.ends_with(&[0]) && nul_count == 1;
+            kinds[si] = Some(if is_one_string { Kind::Strings } else { Kind::Constant });
+        }
+    }
+    kinds
+}
+
+fn split_section(section: &Section, kind: Kind) -> Vec<Piece> {
+    let section_alignment = match (section.characteristics & IMAGE_SCN_ALIGN_MASK) >> 20 {
+        0 => 16,
+        n => 1 << (n - 1),
+    };
+    if kind == Kind::Constant {
+        return vec![Piece {
+            offset: 0,
+            length: u32::try_from(section.raw_data.len()).unwrap(),
+            alignment: section_alignment,
+            merged_offset: 0,
+        }];
+    }
+
+    let mut pieces = Vec::new();
+    let mut offset: u32 = 0;
+    for (i, b) in section.raw_data.iter().enumerate() {
+        if *b != 0 {
+            continue;
+        }
+        let end = u32::try_from(i + 1).unwrap();
+        // A string inside the section is only as aligned as its offset.
+        let alignment = if offset == 0 { section_alignment } else { (1 << offset.trailing_zeros()).min(section_alignment) };
+        pieces.push(Piece {offset, length: end - offset, alignment, merged_offset: 0});
+        offset = end;
+    }
+    pieces
+}
+
+fn piece_data<'a>(section: &'a Section, piece: &Piece) -> &'a [u8] {
+    let offset = usize::try_from(piece.offset).unwrap();
+    &section.raw_data[offset..offset + usize::try_from(piece.length).unwrap()]
+}
+
+/// Maps an offset into a merged section to the matching offset in the merged
+/// data. An offset just past the end stays just past the last piece.
+fn map_offset(pieces: &[Piece], offset: u32) -> u32 {
+    for piece in pieces {
+        if offset >= piece.offset && offset < piece.offset + piece.length {
+            return piece.merged_offset + (offset - piece.offset);
+        }
+    }
+    let last = pieces.last().unwrap();
+    assert_eq!(offset, last.offset + last.length, "offset {:#x} points outside of a mergeable section", offset);
+    last.merged_offset + last.length
+}
+
+fn append_aligned(data: &mut Vec<u8>, piece_data: &[u8], alignment: u32) -> u32 {
+    let alignment = usize::try_from(alignment).unwrap();
+    let offset = data.len().div_ceil(alignment) * alignment;
+    data.resize(offset, 0);
+    data.extend(piece_data);
+    u32::try_from(offset).unwrap()
+}
+
+fn alignment_characteristics(alignment: u32) -> u32 {
+    (alignment.trailing_zeros() + 1) << 20
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::coff::{
+        COFF,
+        Section,
+        SymbolTableRecord,
+        IMAGE_SCN_ALIGN_MASK,
+        IMAGE_SCN_CNT_INITIALIZED_DATA,
+        IMAGE_SCN_LNK_COMDAT,
+        IMAGE_SCN_MEM_READ,
+        create_symbol_for_table,
+    };
+    use super::merge_constants;
+
+    const IMAGE_SCN_ALIGN_4BYTES: u32 = 0x00300000;
+    const IMAGE_SCN_ALIGN_16BYTES: u32 = 0x00500000;
+
+    fn constant_section(raw_data: Vec<u8>, alignment: u32) -> Section {
+        Section {
+            name: ".rdata".to_string(),
+            raw_data,
+            characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_LNK_COMDAT | alignment,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merging_constants_of_mixed_alignments_keeps_them_aligned() {
+        let mut coff = COFF {
+            sections: vec![
+                constant_section(vec![1; 4], IMAGE_SCN_ALIGN_4BYTES),
+                constant_section(vec![2; 16], IMAGE_SCN_ALIGN_16BYTES),
+                constant_section(vec![1; 4], IMAGE_SCN_ALIGN_4BYTES),
+            ],
+            symbols: vec![
+                create_symbol_for_table("__real@01010101".to_string(), 0, 1, false),
+                create_symbol_for_table("__xmm@02020202020202020202020202020202".to_string(), 0, 2, false),
+                create_symbol_for_table("__real@01010101".to_string(), 0, 3, false),
+            ],
+        };
+        let merging = merge_constants(&mut coff);
+        assert_eq!(merging.sections, 3);
+        // 24 bytes of constants take 32 once the __xmm@ one is aligned.
+        assert_eq!(merging.bytes, 0);
+
+        assert_eq!(coff.sections.len(), 1);
+        let merged = &coff.sections[0];
+        assert_eq!(merged.raw_data.len(), 32);
+        assert_eq!(merged.raw_data[..4], [1; 4]);
+        assert_eq!(merged.raw_data[16..], [2; 16]);
+        assert_eq!(merged.characteristics & IMAGE_SCN_ALIGN_MASK, IMAGE_SCN_ALIGN_16BYTES);
+
+        let placements = coff.symbols.iter().map(|symbol| match symbol {
+            SymbolTableRecord::Symbol(s) => (s.section_number, s.value),
+            SymbolTableRecord::Aux(_) => unreachable!(),
+        }).collect::<Vec<(i16, u32)>>();
+        assert_eq!(placements, [(1, 0), (1, 16), (1, 0)]);
+    }
+}
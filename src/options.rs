@@ -0,0 +1,112 @@
+use super::dos::Rich;
+use super::layout::{Fill, parse_fill};
+use super::load_config::Cfg;
+use super::reloc::Aslr;
+use super::pe::{HeaderOverrides, parse_subsystem, parse_subsystem_version};
+
+/// Switches that follow the four positional arguments on the command line.
+#[derive(Default)]
+pub struct Options {
+    /// Drop sections of the object that nothing reaches, like `/OPT:REF`.
+    pub gc_sections: bool,
+    /// Fold identical code and read-only data sections, like `/OPT:ICF`.
+    pub icf: bool,
+    /// Merge identical strings and constants in read-only data.
+    pub merge_strings: bool,
+    /// File listing symbols whose sections should be laid out first.
+    pub order_file: Option<String>,
+    /// Padding to use in output sections, by name.
+    pub fills: Vec<(String, Fill)>,
+    /// Whether to write the image checksum. Unless told otherwise, it's
+    /// written for images that had one.
+    pub checksum: Option<bool>,
+    /// Keep the Authenticode signature even though it no longer matches.
+    pub keep_signature: bool,
+    /// What to do about ASLR in images that have it.
+    pub aslr: Aslr,
+    /// What to do about Control Flow Guard in images that have it.
+    pub cfg: Cfg,
+    /// Symbols to add to the TLS callbacks of the image.
+    pub tls_callbacks: Vec<String>,
+    /// Move a resource section that's last in the image after the new
+    /// sections, so that it stays last.
+    pub rsrc_last: bool,
+    /// File describing changes to the resources of the image.
+    pub resources: Option<String>,
+    /// What to do about the Rich header.
+    pub rich: Rich,
+    /// Header fields to set, these win over the ones in image_info.
+    pub header: HeaderOverrides,
+}
+
+pub fn parse_options(args: &[String]) -> Options {
+    let mut options = Options::default();
+    for arg in args {
+        match arg.as_str() {
+            "--gc-sections" => options.gc_sections = true,
+            "--icf" => options.icf = true,
+            "--merge-strings" => options.merge_strings = true,
+            "--checksum" => options.checksum = Some(true),
+            "--no-checksum" => options.checksum = Some(false),
+            "--keep-signature" => options.keep_signature = true,
+            "--rsrc-last" => options.rsrc_last = true,
+            _ => {
+                if let Some(path) = arg.strip_prefix("--order-file=") {
+                    options.order_file = Some(path.to_string());
+                } else if let Some(fill) = arg.strip_prefix("--fill=") {
+                    let (name, fill_str) = fill.split_once('=').unwrap_or_else(|| panic!("expected --fill=SECTION=FILL, got {}", arg));
+                    options.fills.push((name.to_string(), parse_fill(fill_str)));
+                } else if let Some(aslr) = arg.strip_prefix("--aslr=") {
+                    options.aslr = match aslr {
+                        "relocate" => Aslr::Relocate,
+                        "disable" => Aslr::Disable,
+                        _ => panic!("expected --aslr=relocate or --aslr=disable, got {}", arg),
+                    };
+                } else if let Some(cfg) = arg.strip_prefix("--cfg=") {
+                    options.cfg = match cfg {
+                        "register" => Cfg::Register,
+                        "clear" => Cfg::Clear,
+                        _ => panic!("expected --cfg=register or --cfg=clear, got {}", arg),
+                    };
+                } else if let Some(path) = arg.strip_prefix("--resources=") {
+                    options.resources = Some(path.to_string());
+                } else if let Some(rich) = arg.strip_prefix("--rich=") {
+                    options.rich = match rich {
+                        "keep" => Rich::Keep,
+                        "strip" => Rich::Strip,
+                        "recompute" => Rich::Recompute,
+                        _ => panic!("expected --rich=keep, --rich=strip or --rich=recompute, got {}", arg),
+                    };
+                } else if let Some(name) = arg.strip_prefix("--tls-callback=") {
+                    options.tls_callbacks.push(name.to_string());
+                } else if let Some(size) = arg.strip_prefix("--stack-reserve=") {
+                    options.header.stack_reserve = Some(parse_number(size));
+                } else if let Some(size) = arg.strip_prefix("--stack-commit=") {
+                    options.header.stack_commit = Some(parse_number(size));
+                } else if let Some(size) = arg.strip_prefix("--heap-reserve=") {
+                    options.header.heap_reserve = Some(parse_number(size));
+                } else if let Some(size) = arg.strip_prefix("--heap-commit=") {
+                    options.header.heap_commit = Some(parse_number(size));
+                } else if let Some(subsystem) = arg.strip_prefix("--subsystem=") {
+                    options.header.subsystem = Some(parse_subsystem(subsystem));
+                } else if let Some(version) = arg.strip_prefix("--subsystem-version=") {
+                    options.header.subsystem_version = Some(parse_subsystem_version(version));
+                } else if let Some(time_date_stamp) = arg.strip_prefix("--timestamp=") {
+                    options.header.time_date_stamp = Some(parse_number(time_date_stamp));
+                } else {
+                    panic!("unknown option {}", arg);
+                }
+            }
+        }
+    }
+    options
+}
+
+/// Parses a decimal or "0x"-prefixed hexadecimal number.
+fn parse_number(number_str: &str) -> u32 {
+    let number = match number_str.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number_str.parse::<u32>(),
+    };
+    number.unwrap_or_else(|_| panic!("invalid number {}", number_str))
+}
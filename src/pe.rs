@@ -1,24 +1,99 @@
-use std::io::{Write, Seek, SeekFrom};
+use std::io::{Seek, SeekFrom};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 
-use super::coff::{read_sections, Section};
+use super::coff::{
+    read_sections,
+    Section,
+    IMAGE_SCN_CNT_CODE,
+    IMAGE_SCN_CNT_INITIALIZED_DATA,
+    IMAGE_SCN_CNT_UNINITIALIZED_DATA,
+};
+use super::dos::{Stub, read_stub, write_stub};
 
 const PE_SIGNATURE_OFFSET: u64 = 0x3c;
+const SECTION_HEADER_SIZE: u32 = 40;
+const DEBUG_DIRECTORY_SIZE: u32 = 28;
+
+const IMAGE_NUMBEROF_DIRECTORY_ENTRIES: usize = 16;
+
+pub const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+pub const IMAGE_DLLCHARACTERISTICS_NO_SEH: u16 = 0x0400;
+pub const IMAGE_DLLCHARACTERISTICS_GUARD_CF: u16 = 0x4000;
+
+/// The entries of the data directory irl reads or writes, by their index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DirectoryEntry {
+    Export = 0,
+    Import = 1,
+    Resource = 2,
+    /// The certificate table, the one directory given by file offset.
+    Security = 4,
+    BaseReloc = 5,
+    Debug = 6,
+    Tls = 9,
+    LoadConfig = 10,
+    BoundImport = 11,
+}
 
 pub fn read_image<F: ReadBytesExt + Seek>(mut pe: F) -> Image {
     pe.seek(SeekFrom::Start(PE_SIGNATURE_OFFSET)).unwrap();
     let pe_signature_position = pe.read_u32::<LittleEndian>().unwrap();
     pe.seek(SeekFrom::Start(0)).unwrap();
-    let mut stub = vec![0; usize::try_from(pe_signature_position).unwrap()];
-    pe.read_exact(&mut stub).unwrap();
+    let mut stub_bytes = vec![0; usize::try_from(pe_signature_position).unwrap()];
+    pe.read_exact(&mut stub_bytes).unwrap();
+    let stub = read_stub(&stub_bytes);
 
     let pe_signature = pe.read_u32::<LittleEndian>().unwrap();
     assert_eq!(pe_signature, 0x00004550);
     let coff_header = super::coff::read_header(&mut pe);
+    let optional_header_position = pe.stream_position().unwrap();
     let optional_header = read_optional_header(&mut pe);
     let data_directories = read_data_directories(&mut pe, optional_header.number_of_rva_and_sizes);
+    let data_directories_end = pe.stream_position().unwrap();
+    // The section table follows SizeOfOptionalHeader, not the last directory.
+    let section_table_position = optional_header_position + u64::from(coff_header.size_of_optional_header);
+    pe.seek(SeekFrom::Start(section_table_position)).unwrap();
     let sections = read_sections(&mut pe, coff_header.number_of_sections, &[]);
+    let section_table_end = pe.stream_position().unwrap();
+
+    // Whatever follows the raw data of the last section isn't described by
+    // the headers at all: signatures, installer payloads, archives.
+    let mut overlay_offset = optional_header.size_of_headers;
+    for section in &sections {
+        if !section.raw_data.is_empty() {
+            overlay_offset = overlay_offset.max(section.pointer_to_raw_data + u32::try_from(section.raw_data.len()).unwrap());
+        }
+    }
+    pe.seek(SeekFrom::Start(u64::from(overlay_offset))).unwrap();
+    let mut overlay = Vec::new();
+    pe.read_to_end(&mut overlay).unwrap();
+
+    // Everything before the overlay that isn't a header or raw data of a
+    // section: the padding after the section table, which may hold bound
+    // imports, and whatever is between sections.
+    let mut covered = vec![
+        (0, u32::try_from(data_directories_end).unwrap()),
+        (u32::try_from(section_table_position).unwrap(), u32::try_from(section_table_end).unwrap()),
+    ];
+    for section in &sections {
+        if !section.raw_data.is_empty() {
+            covered.push((section.pointer_to_raw_data, section.pointer_to_raw_data + u32::try_from(section.raw_data.len()).unwrap()));
+        }
+    }
+    covered.push((overlay_offset, overlay_offset));
+    covered.sort();
+    let mut gaps = Vec::new();
+    let mut position = 0;
+    for (start, end) in covered {
+        if start > position {
+            let mut data = vec![0; usize::try_from(start - position).unwrap()];
+            pe.seek(SeekFrom::Start(u64::from(position))).unwrap();
+            pe.read_exact(&mut data).unwrap();
+            gaps.push(Gap {offset: position, data});
+        }
+        position = position.max(end);
+    }
 
     Image {
         stub,
@@ -27,31 +102,577 @@ pub fn read_image<F: ReadBytesExt + Seek>(mut pe: F) -> Image {
         optional_header,
         data_directories,
         sections,
+        overlay_offset,
+        overlay,
+        gaps,
     }
 }
 
-pub fn write_image<F: WriteBytesExt + Seek>(image: Image, destination: &mut F) {
-    destination.write_all(&image.stub).unwrap();
+pub fn write_image<F: WriteBytesExt + Seek>(mut image: Image, destination: &mut F) {
+    image.place_overlay();
+    let overlay_offset = image.overlay_offset;
+    let overlay = std::mem::take(&mut image.overlay);
+    let size_of_headers = u64::from(image.optional_header.size_of_headers);
+    let section_table_position = u64::from(image.stub.size()) + 4 + 20 + u64::from(image.coff_header.size_of_optional_header);
+
+    // Gaps go first, so that the headers and sections win wherever they
+    // have grown into one.
+    for gap in &image.gaps {
+        destination.seek(SeekFrom::Start(u64::from(gap.offset))).unwrap();
+        destination.write_all(&gap.data).unwrap();
+    }
+
+    destination.seek(SeekFrom::Start(0)).unwrap();
+    let mut stub_bytes = Vec::new();
+    write_stub(&image.stub, &mut stub_bytes);
+    destination.write_all(&stub_bytes).unwrap();
     destination.write_u32::<LittleEndian>(image.pe_signature).unwrap();
     super::coff::write_header(image.coff_header, destination);
     write_optional_header(image.optional_header, destination);
     write_data_directories(image.data_directories, destination);
+    let position = destination.stream_position().unwrap();
+    assert!(position <= section_table_position, "the data directories run into the section table");
+    destination.seek(SeekFrom::Start(section_table_position)).unwrap();
     super::coff::write_sections(&image.sections, destination);
     let position = destination.stream_position().unwrap();
-    let padding = vec![0; 0x1000 - usize::try_from(position).unwrap()];
-    destination.write_all(&padding).unwrap();
+    assert!(position <= size_of_headers, "the headers take {:#x} bytes, but only {:#x} fit before the first section", position, size_of_headers);
+    let length = destination.seek(SeekFrom::End(0)).unwrap();
+    if length < size_of_headers {
+        destination.write_all(&vec![0; usize::try_from(size_of_headers - length).unwrap()]).unwrap();
+    }
     for section in image.sections {
+        if section.raw_data.is_empty() || section.pointer_to_raw_data == 0 {
+            continue;
+        }
+        destination.seek(SeekFrom::Start(u64::from(section.pointer_to_raw_data))).unwrap();
         destination.write_all(&section.raw_data).unwrap();
     }
+    if !overlay.is_empty() {
+        destination.seek(SeekFrom::Start(u64::from(overlay_offset))).unwrap();
+        destination.write_all(&overlay).unwrap();
+    }
+}
+
+/// How much memory a section takes before section alignment. A VirtualSize
+/// of zero means the size of the raw data is used instead.
+fn virtual_extent(section: &Section) -> u32 {
+    if section.virtual_size == 0 {
+        return u32::try_from(section.raw_data.len()).unwrap();
+    }
+    section.virtual_size
+}
+
+/// Computes the image checksum the way `MapFileAndCheckSum` does and stores
+/// it in the CheckSum field of `image_bytes`, a complete image.
+pub fn write_checksum(image_bytes: &mut [u8]) {
+    let offset = checksum_offset(image_bytes);
+    image_bytes[offset..offset + 4].fill(0);
+    let checksum = compute_checksum(image_bytes);
+    image_bytes[offset..offset + 4].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// Sums the image as 16-bit words with the carries folded back in and adds
+/// the length of the image. The CheckSum field must be zero.
+fn compute_checksum(image_bytes: &[u8]) -> u32 {
+    let mut sum: u32 = 0;
+    for word in image_bytes.chunks(2) {
+        let word = match word {
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            // An odd trailing byte is padded with a zero.
+            [low] => u16::from(*low),
+            _ => unreachable!(),
+        };
+        sum += u32::from(word);
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum + u32::try_from(image_bytes.len()).unwrap()
+}
+
+fn checksum_offset(image_bytes: &[u8]) -> usize {
+    let pe_signature_offset = usize::try_from(PE_SIGNATURE_OFFSET).unwrap();
+    let pe_signature_position = u32::from_le_bytes(image_bytes[pe_signature_offset..pe_signature_offset + 4].try_into().unwrap());
+    // The signature, the COFF header and 64 bytes into the optional header.
+    usize::try_from(pe_signature_position).unwrap() + 4 + 20 + 64
+}
+
+pub fn calculate_aligned_size(size: u32, alignment: u32) -> u32 {
+    let alignment_difference = size % alignment;
+    if alignment_difference != 0 {
+        return size + alignment - alignment_difference;
+    }
+    size
+}
+
+/// Header fields to set in the output instead of keeping the image's.
+#[derive(Default)]
+pub struct HeaderOverrides {
+    pub stack_reserve: Option<u32>,
+    pub stack_commit: Option<u32>,
+    pub heap_reserve: Option<u32>,
+    pub heap_commit: Option<u32>,
+    pub subsystem: Option<u16>,
+    pub subsystem_version: Option<(u16, u16)>,
+    pub time_date_stamp: Option<u32>,
+}
+
+impl HeaderOverrides {
+    /// Takes every field that isn't set here from `fallback`.
+    pub fn or(self, fallback: HeaderOverrides) -> HeaderOverrides {
+        HeaderOverrides {
+            stack_reserve: self.stack_reserve.or(fallback.stack_reserve),
+            stack_commit: self.stack_commit.or(fallback.stack_commit),
+            heap_reserve: self.heap_reserve.or(fallback.heap_reserve),
+            heap_commit: self.heap_commit.or(fallback.heap_commit),
+            subsystem: self.subsystem.or(fallback.subsystem),
+            subsystem_version: self.subsystem_version.or(fallback.subsystem_version),
+            time_date_stamp: self.time_date_stamp.or(fallback.time_date_stamp),
+        }
+    }
+}
+
+/// Parses a subsystem given as "native", "windows", "console" or a number.
+pub fn parse_subsystem(subsystem_str: &str) -> u16 {
+    match subsystem_str {
+        "native" => 1,
+        "windows" => 2,
+        "console" => 3,
+        _ => subsystem_str.parse::<u16>().unwrap_or_else(|_| panic!("invalid subsystem {}", subsystem_str)),
+    }
+}
+
+/// Parses a subsystem version such as "6.0".
+pub fn parse_subsystem_version(version_str: &str) -> (u16, u16) {
+    let parsed = version_str.split_once('.').map(|(major, minor)| (major.parse::<u16>(), minor.parse::<u16>()));
+    match parsed {
+        Some((Ok(major), Ok(minor))) => (major, minor),
+        _ => panic!("invalid subsystem version {}, expected MAJOR.MINOR", version_str),
+    }
+}
+
+/// What was done to make the headers fit the section table.
+#[derive(Default)]
+pub struct HeaderGrowth {
+    /// Bytes of the DOS stub program (and the Rich header in it) that were
+    /// given over to the headers.
+    pub stub_bytes_reused: u32,
+    /// How far the raw data of every section was moved forward.
+    pub raw_data_shift: u32,
+}
+
+impl Image {
+    /// The size of everything up to the end of the section table.
+    pub fn headers_size(&self) -> u32 {
+        self.stub.size()
+            + 4
+            + 20
+            + u32::from(self.coff_header.size_of_optional_header)
+            + SECTION_HEADER_SIZE * u32::try_from(self.sections.len()).unwrap()
+    }
+
+    /// Makes room for the section table when sections were added and it no
+    /// longer fits in SizeOfHeaders. The raw data of every section is moved
+    /// forward by a multiple of FileAlignment, as long as the headers don't
+    /// run into the first section in memory. When they would, the space of
+    /// the DOS stub program is reused first.
+    pub fn grow_headers(&mut self) -> HeaderGrowth {
+        let mut growth = HeaderGrowth::default();
+        let file_alignment = self.optional_header.file_alignment;
+        let first_virtual_address = self.sections.iter().map(|s| s.virtual_address).min().unwrap();
+        if self.headers_size() <= self.optional_header.size_of_headers {
+            return growth;
+        }
+
+        if calculate_aligned_size(self.headers_size(), file_alignment) > first_virtual_address {
+            growth.stub_bytes_reused = self.stub.truncate();
+            if self.headers_size() <= self.optional_header.size_of_headers {
+                return growth;
+            }
+        }
+
+        let new_size_of_headers = calculate_aligned_size(self.headers_size(), file_alignment);
+        assert!(new_size_of_headers <= first_virtual_address,
+                "the section table needs {:#x} bytes of headers, but only {:#x} fit before the first section",
+                new_size_of_headers, first_virtual_address);
+        growth.raw_data_shift = new_size_of_headers - self.optional_header.size_of_headers;
+        self.shift_raw_data(growth.raw_data_shift);
+        self.optional_header.size_of_headers = new_size_of_headers;
+        growth
+    }
+
+    /// Derives the header fields that describe the section table from it and
+    /// checks that the sections are sorted and contiguous in memory and don't
+    /// overlap in the file. Meant to run once nothing changes the sections
+    /// any more, right before writing.
+    pub fn finalize(&mut self) {
+        let section_alignment = self.optional_header.section_alignment;
+        let file_alignment = self.optional_header.file_alignment;
+
+        let mut next_virtual_address = None;
+        for section in &self.sections {
+            let name = section.name.trim_end_matches('\0');
+            match next_virtual_address {
+                None => assert!(section.virtual_address >= calculate_aligned_size(self.optional_header.size_of_headers, section_alignment),
+                                "section {} at {:#x} overlaps the headers", name, section.virtual_address),
+                Some(next) => assert!(section.virtual_address == next,
+                                      "section {} is at {:#x} rather than right after the section before it at {:#x}", name, section.virtual_address, next),
+            }
+            next_virtual_address = Some(section.virtual_address + calculate_aligned_size(virtual_extent(section), section_alignment));
+        }
+
+        let mut raw_data_ranges = self.sections.iter()
+            .filter(|s| !s.raw_data.is_empty())
+            .map(|s| (s.pointer_to_raw_data, s.pointer_to_raw_data + u32::try_from(s.raw_data.len()).unwrap()))
+            .collect::<Vec<(u32, u32)>>();
+        raw_data_ranges.sort();
+        let mut end_of_previous = self.optional_header.size_of_headers;
+        for (start, end) in raw_data_ranges {
+            assert!(start >= end_of_previous, "raw data at {:#x} overlaps the headers or another section", start);
+            end_of_previous = end;
+        }
+
+        let mut size_of_code = 0;
+        let mut size_of_initialized_data = 0;
+        let mut size_of_uninitialized_data = 0;
+        let mut base_of_code = None;
+        for section in &self.sections {
+            let size_of_raw_data = calculate_aligned_size(u32::try_from(section.raw_data.len()).unwrap(), file_alignment);
+            if section.characteristics & IMAGE_SCN_CNT_CODE != 0 {
+                size_of_code += size_of_raw_data;
+                base_of_code.get_or_insert(section.virtual_address);
+            }
+            if section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 {
+                size_of_initialized_data += size_of_raw_data;
+            }
+            if section.characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA != 0 {
+                size_of_uninitialized_data += calculate_aligned_size(virtual_extent(section), file_alignment);
+            }
+        }
+
+        // Bound imports usually sit in the header padding right after the
+        // section table. The loader would read new section headers as bound
+        // imports, while without them it simply resolves the imports itself.
+        if let Some(bound_import) = self.directory(DirectoryEntry::BoundImport) {
+            if bound_import.virtual_address < self.headers_size() {
+                self.set_directory(DirectoryEntry::BoundImport, DataDirectory::default());
+            }
+        }
+
+        self.coff_header.number_of_sections = u16::try_from(self.sections.len()).unwrap();
+        self.optional_header.size_of_code = size_of_code;
+        self.optional_header.size_of_initialized_data = size_of_initialized_data;
+        self.optional_header.size_of_uninitialized_data = size_of_uninitialized_data;
+        self.optional_header.base_of_code = base_of_code.unwrap_or(0);
+        self.optional_header.size_of_image = next_virtual_address.unwrap();
+    }
+
+    /// Sets the header fields given in `overrides`, after checking that the
+    /// result makes sense.
+    pub fn override_header(&mut self, overrides: &HeaderOverrides) {
+        let header = &mut self.optional_header;
+        header.size_of_stack_reserve = overrides.stack_reserve.unwrap_or(header.size_of_stack_reserve);
+        header.size_of_stack_commit = overrides.stack_commit.unwrap_or(header.size_of_stack_commit);
+        header.size_of_heap_reserve = overrides.heap_reserve.unwrap_or(header.size_of_heap_reserve);
+        header.size_of_heap_commit = overrides.heap_commit.unwrap_or(header.size_of_heap_commit);
+        header.subsystem = overrides.subsystem.unwrap_or(header.subsystem);
+        if let Some((major, minor)) = overrides.subsystem_version {
+            header.major_subsystem_version = major;
+            header.minor_subsystem_version = minor;
+        }
+        if let Some(time_date_stamp) = overrides.time_date_stamp {
+            self.coff_header.time_date_stamp = time_date_stamp;
+        }
+
+        assert!(header.size_of_stack_commit <= header.size_of_stack_reserve,
+                "the stack commit {:#x} is larger than the stack reserve {:#x}", header.size_of_stack_commit, header.size_of_stack_reserve);
+        assert!(header.size_of_heap_commit <= header.size_of_heap_reserve,
+                "the heap commit {:#x} is larger than the heap reserve {:#x}", header.size_of_heap_commit, header.size_of_heap_reserve);
+        assert_ne!(header.subsystem, 0, "the subsystem can't be unknown");
+    }
+
+    /// The directory entry, unless the image doesn't have that directory.
+    pub fn directory(&self, entry: DirectoryEntry) -> Option<DataDirectory> {
+        match self.data_directories.get(entry as usize) {
+            Some(d) if d.virtual_address != 0 => Some(*d),
+            _ => None,
+        }
+    }
+
+    /// Points the directory entry at `directory`, adding entries to the data
+    /// directory if the image has fewer. An empty `directory` removes it.
+    pub fn set_directory(&mut self, entry: DirectoryEntry, directory: DataDirectory) {
+        let index = entry as usize;
+        if index >= self.data_directories.len() {
+            if directory.virtual_address == 0 {
+                return;
+            }
+            let added = IMAGE_NUMBEROF_DIRECTORY_ENTRIES - self.data_directories.len();
+            self.data_directories.resize(IMAGE_NUMBEROF_DIRECTORY_ENTRIES, DataDirectory::default());
+            self.optional_header.number_of_rva_and_sizes = u32::try_from(IMAGE_NUMBEROF_DIRECTORY_ENTRIES).unwrap();
+            self.coff_header.size_of_optional_header += u16::try_from(added * 8).unwrap();
+        }
+        self.data_directories[index] = directory;
+    }
+
+    /// The bytes a directory entry points at, from the overlay for the
+    /// certificate table and from section data for everything else.
+    pub fn directory_data(&self, entry: DirectoryEntry) -> Option<&[u8]> {
+        let directory = self.directory(entry)?;
+        if entry == DirectoryEntry::Security {
+            let start = usize::try_from(directory.virtual_address.checked_sub(self.overlay_offset)?).unwrap();
+            return self.overlay.get(start..start + usize::try_from(directory.size).unwrap());
+        }
+        self.section_data(directory.virtual_address, directory.size)
+    }
+
+    /// Whether the image carries an Authenticode signature.
+    pub fn has_signature(&self) -> bool {
+        self.directory(DirectoryEntry::Security).is_some()
+    }
+
+    /// Removes the certificate table from the overlay and clears the security
+    /// directory.
+    pub fn strip_signature(&mut self) {
+        let security = self.directory(DirectoryEntry::Security).unwrap();
+        assert!(security.virtual_address >= self.overlay_offset, "the certificate table isn't in the overlay");
+        let start = usize::try_from(security.virtual_address - self.overlay_offset).unwrap();
+        let end = start + usize::try_from(security.size).unwrap();
+        self.overlay.drain(start..end.min(self.overlay.len()));
+        self.set_directory(DirectoryEntry::Security, DataDirectory::default());
+    }
+
+    /// Moves the overlay right after the raw data of the last section and the
+    /// certificate table in it along with it. The certificate table has to
+    /// stay 8-byte aligned, so the overlay keeps its alignment.
+    fn place_overlay(&mut self) {
+        let mut end = self.optional_header.size_of_headers;
+        for section in &self.sections {
+            if !section.raw_data.is_empty() {
+                end = end.max(section.pointer_to_raw_data + u32::try_from(section.raw_data.len()).unwrap());
+            }
+        }
+        let offset = end + (self.overlay_offset.wrapping_sub(end) & 7);
+        let overlay_offset = self.overlay_offset;
+        let move_pointer = |pointer: &mut u32| {
+            if *pointer != 0 && *pointer >= overlay_offset {
+                *pointer = *pointer - overlay_offset + offset;
+            }
+        };
+        if let Some(mut security) = self.directory(DirectoryEntry::Security) {
+            move_pointer(&mut security.virtual_address);
+            self.set_directory(DirectoryEntry::Security, security);
+        }
+        // The COFF symbol table and line numbers MinGW leaves in images.
+        move_pointer(&mut self.coff_header.pointer_to_symbol_table);
+        for section in &mut self.sections {
+            move_pointer(&mut section.pointer_to_relocations);
+            move_pointer(&mut section.pointer_to_linenumbers);
+        }
+        self.overlay_offset = offset;
+    }
+
+    /// Moves the raw data of every section `shift` bytes forward along with
+    /// everything that refers to it by file offset.
+    fn shift_raw_data(&mut self, shift: u32) {
+        let size_of_headers = self.optional_header.size_of_headers;
+        let shift_pointer = |pointer: &mut u32| {
+            if *pointer != 0 && *pointer >= size_of_headers {
+                *pointer += shift;
+            }
+        };
+        for section in &mut self.sections {
+            shift_pointer(&mut section.pointer_to_raw_data);
+            shift_pointer(&mut section.pointer_to_relocations);
+            shift_pointer(&mut section.pointer_to_linenumbers);
+        }
+        shift_pointer(&mut self.coff_header.pointer_to_symbol_table);
+        // Gaps in the header padding stay where they are.
+        for gap in &mut self.gaps {
+            shift_pointer(&mut gap.offset);
+        }
+
+        // The certificate table is the one directory given by file offset. It
+        // moves along with the rest of the overlay.
+        if let Some(mut security) = self.directory(DirectoryEntry::Security) {
+            shift_pointer(&mut security.virtual_address);
+            self.set_directory(DirectoryEntry::Security, security);
+        }
+        self.overlay_offset += shift;
+
+        let debug = match self.directory(DirectoryEntry::Debug) {
+            Some(d) => d,
+            None => return,
+        };
+        let debug_data = self.directory_data(DirectoryEntry::Debug).expect("the debug directory isn't in a section");
+        // AddressOfRawData is the second to last field of IMAGE_DEBUG_DIRECTORY.
+        let addresses_of_raw_data = debug_data.chunks_exact(usize::try_from(DEBUG_DIRECTORY_SIZE).unwrap())
+            .map(|entry| u32::from_le_bytes(entry[20..24].try_into().unwrap()))
+            .collect::<Vec<u32>>();
+        for (di, address_of_raw_data) in (0..).zip(addresses_of_raw_data) {
+            // Mapped debug data is wherever its section is now, the rest is
+            // in the overlay or a gap and moves by the shift.
+            let mapped_pointer = match address_of_raw_data {
+                0 => None,
+                rva => self.rva_to_file_offset(rva),
+            };
+            // PointerToRawData is the last one.
+            let field = self.section_data_mut(debug.virtual_address + di * DEBUG_DIRECTORY_SIZE + 24, 4).unwrap();
+            let pointer_to_raw_data = u32::from_le_bytes((&*field).try_into().unwrap());
+            if pointer_to_raw_data != 0 {
+                let pointer_to_raw_data = mapped_pointer.unwrap_or(pointer_to_raw_data + shift);
+                field.copy_from_slice(&pointer_to_raw_data.to_le_bytes());
+            }
+        }
+    }
+
+    pub fn rva_to_va(&self, rva: u32) -> u32 {
+        self.optional_header.image_base + rva
+    }
+
+    /// The RVA of a virtual address, if it isn't below the image base.
+    pub fn va_to_rva(&self, virtual_address: u32) -> Option<u32> {
+        virtual_address.checked_sub(self.optional_header.image_base)
+    }
+
+    /// The index of the section whose memory holds `rva`, from its first
+    /// byte up to, but not including, the end of its VirtualSize.
+    pub fn section_index_for_rva(&self, rva: u32) -> Option<usize> {
+        self.sections.iter().position(|s| rva >= s.virtual_address && rva - s.virtual_address < virtual_extent(s))
+    }
+
+    /// Where the byte at `rva` comes from in the file. The headers are
+    /// mapped as they are, uninitialized data comes from nowhere.
+    pub fn rva_to_file_offset(&self, rva: u32) -> Option<u32> {
+        if rva < self.optional_header.size_of_headers {
+            return Some(rva);
+        }
+        let section = &self.sections[self.section_index_for_rva(rva)?];
+        let offset = rva - section.virtual_address;
+        if offset >= u32::try_from(section.raw_data.len()).unwrap() {
+            return None;
+        }
+        Some(section.pointer_to_raw_data + offset)
+    }
+
+    /// Where the byte at `file_offset` ends up in memory. Raw data past the
+    /// VirtualSize of its section isn't mapped at all.
+    pub fn file_offset_to_rva(&self, file_offset: u32) -> Option<u32> {
+        if file_offset < self.optional_header.size_of_headers {
+            return Some(file_offset);
+        }
+        self.sections.iter().find_map(|s| {
+            let raw_data_length = u32::try_from(s.raw_data.len()).unwrap().min(virtual_extent(s));
+            let in_section = s.pointer_to_raw_data != 0 && file_offset >= s.pointer_to_raw_data && file_offset - s.pointer_to_raw_data < raw_data_length;
+            in_section.then(|| s.virtual_address + (file_offset - s.pointer_to_raw_data))
+        })
+    }
+
+    /// Whether all of `length` bytes at `rva` are initialized data of a
+    /// single section, so that they can be read and patched.
+    pub fn is_initialized_data(&self, rva: u32, length: u32) -> bool {
+        let section = match self.section_index_for_rva(rva) {
+            Some(si) => &self.sections[si],
+            None => return false,
+        };
+        let end = (rva - section.virtual_address).checked_add(length);
+        let raw_data_length = u32::try_from(section.raw_data.len()).unwrap().min(virtual_extent(section));
+        matches!(end, Some(end) if end <= raw_data_length)
+    }
+
+    pub fn section_data(&self, rva: u32, length: u32) -> Option<&[u8]> {
+        if !self.is_initialized_data(rva, length) {
+            return None;
+        }
+        let section = &self.sections[self.section_index_for_rva(rva).unwrap()];
+        let offset = usize::try_from(rva - section.virtual_address).unwrap();
+        Some(&section.raw_data[offset..offset + usize::try_from(length).unwrap()])
+    }
+
+    pub fn section_data_mut(&mut self, rva: u32, length: u32) -> Option<&mut [u8]> {
+        if !self.is_initialized_data(rva, length) {
+            return None;
+        }
+        let si = self.section_index_for_rva(rva).unwrap();
+        let section = &mut self.sections[si];
+        let offset = usize::try_from(rva - section.virtual_address).unwrap();
+        Some(&mut section.raw_data[offset..offset + usize::try_from(length).unwrap()])
+    }
+
+    pub fn read_u32(&self, rva: u32) -> u32 {
+        let data = self.section_data(rva, 4).unwrap_or_else(|| panic!("RVA {:#010x} isn't in initialized data", rva));
+        u32::from_le_bytes(data.try_into().unwrap())
+    }
+
+    pub fn write_u32(&mut self, rva: u32, value: u32) {
+        let data = self.section_data_mut(rva, 4).unwrap_or_else(|| panic!("RVA {:#010x} isn't in initialized data", rva));
+        data.copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Reads the NUL-terminated string at `rva`.
+    pub fn read_cstring(&self, rva: u32) -> String {
+        let si = self.section_index_for_rva(rva).unwrap_or_else(|| panic!("the string at RVA {:#010x} isn't in a section", rva));
+        let section = &self.sections[si];
+        let data = &section.raw_data[usize::try_from(rva - section.virtual_address).unwrap()..];
+        let length = data.iter().position(|&b| b == 0).unwrap_or_else(|| panic!("the string at RVA {:#010x} isn't terminated", rva));
+        String::from_utf8_lossy(&data[..length]).into_owned()
+    }
+
+    /// Appends `section` to the image, right after the last section in memory
+    /// and in the file. Its raw data is padded with zeros to FileAlignment.
+    /// Returns the index of the section.
+    pub fn append_section(&mut self, mut section: Section) -> usize {
+        section.virtual_address = self.next_virtual_address();
+        if section.virtual_size == 0 {
+            section.virtual_size = u32::try_from(section.raw_data.len()).unwrap();
+        }
+        let raw_data_length = calculate_aligned_size(u32::try_from(section.raw_data.len()).unwrap(), self.optional_header.file_alignment);
+        section.raw_data.resize(usize::try_from(raw_data_length).unwrap(), 0);
+        section.pointer_to_raw_data = match raw_data_length {
+            0 => 0,
+            _ => self.next_pointer_to_raw_data(),
+        };
+        self.sections.push(section);
+        self.sections.len() - 1
+    }
+
+    /// The RVA right after the last section in memory, where a new section
+    /// goes.
+    pub fn next_virtual_address(&self) -> u32 {
+        let last_section = self.sections.last().unwrap();
+        last_section.virtual_address + calculate_aligned_size(virtual_extent(last_section), self.optional_header.section_alignment)
+    }
+
+    /// The file offset of the first FileAlignment boundary after the headers
+    /// and the raw data of every section, where a new section's raw data goes.
+    pub fn next_pointer_to_raw_data(&self) -> u32 {
+        let mut end = self.optional_header.size_of_headers;
+        for section in &self.sections {
+            if !section.raw_data.is_empty() {
+                end = end.max(section.pointer_to_raw_data + u32::try_from(section.raw_data.len()).unwrap());
+            }
+        }
+        calculate_aligned_size(end, self.optional_header.file_alignment)
+    }
 }
 
 pub struct Image {
-    stub: Vec<u8>,
+    pub stub: Stub,
     pe_signature: u32,
     pub coff_header: super::coff::Header,
     pub optional_header: OptionalHeader,
     data_directories: Vec<DataDirectory>,
     pub sections: Vec<Section>,
+    /// Where the overlay starts in the file.
+    overlay_offset: u32,
+    /// Everything after the raw data of the last section.
+    overlay: Vec<u8>,
+    /// Bytes of the file before the overlay that nothing in the headers
+    /// describes.
+    gaps: Vec<Gap>,
+}
+
+struct Gap {
+    offset: u32,
+    data: Vec<u8>,
 }
 
 pub struct OptionalHeader {
@@ -76,9 +697,9 @@ pub struct OptionalHeader {
     win32_version_value: u32,
     pub size_of_image: u32,
     size_of_headers: u32,
-    check_sum: u32,
+    pub check_sum: u32,
     subsystem: u16,
-    dll_characteristics: u16,
+    pub dll_characteristics: u16,
     size_of_stack_reserve: u32,
     size_of_stack_commit: u32,
     size_of_heap_reserve: u32,
@@ -87,9 +708,11 @@ pub struct OptionalHeader {
     number_of_rva_and_sizes: u32,
 }
 
-struct DataDirectory {
-    virtual_address: u32,
-    size: u32,
+#[derive(Clone, Copy, Default)]
+pub struct DataDirectory {
+    /// An RVA, except for the certificate table, where it's a file offset.
+    pub virtual_address: u32,
+    pub size: u32,
 }
 
 fn read_optional_header<F: ReadBytesExt>(pe: &mut F) -> OptionalHeader {
@@ -208,3 +831,155 @@ fn write_data_directories<F: WriteBytesExt>(directories: Vec<DataDirectory>, des
         destination.write_u32::<LittleEndian>(directory.size).unwrap();
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+
+    use byteorder::{WriteBytesExt, LittleEndian};
+
+    use super::{read_image, write_checksum, write_image};
+
+    /// A PE32 image with everything a round trip could lose: a DOS stub, an
+    /// optional header longer than its directories, bound imports in the
+    /// header padding, line number fields, a gap between sections, a COFF
+    /// symbol table and a certificate table in the overlay.
+    pub fn synthetic_image() -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend(b"MZ");
+        image.resize(0x3c, 0x11);
+        image.write_u32::<LittleEndian>(0x80).unwrap();
+        image.resize(0x80, 0x22);
+
+        image.extend(b"PE\0\0");
+        image.write_u16::<LittleEndian>(0x014c).unwrap();
+        image.write_u16::<LittleEndian>(2).unwrap();
+        image.write_u32::<LittleEndian>(0x12345678).unwrap();
+        // PointerToSymbolTable and NumberOfSymbols.
+        image.write_u32::<LittleEndian>(0xa00).unwrap();
+        image.write_u32::<LittleEndian>(1).unwrap();
+        image.write_u16::<LittleEndian>(0xe0 + 8).unwrap();
+        image.write_u16::<LittleEndian>(0x0102).unwrap();
+
+        image.write_u16::<LittleEndian>(0x010b).unwrap();
+        image.write_u8(14).unwrap();
+        image.write_u8(0).unwrap();
+        for field in [0x200, 0x200, 0, 0x1000, 0x1000, 0x2000, 0x400000, 0x1000, 0x200] {
+            image.write_u32::<LittleEndian>(field).unwrap();
+        }
+        for field in [6, 0, 0, 0, 6, 0] {
+            image.write_u16::<LittleEndian>(field).unwrap();
+        }
+        for field in [0, 0x3000, 0x400, 0xdeadbeef] {
+            image.write_u32::<LittleEndian>(field).unwrap();
+        }
+        image.write_u16::<LittleEndian>(3).unwrap();
+        image.write_u16::<LittleEndian>(0x8140).unwrap();
+        for field in [0x100000, 0x1000, 0x100000, 0x1000, 0, 16] {
+            image.write_u32::<LittleEndian>(field).unwrap();
+        }
+        for di in 0..16 {
+            let (virtual_address, size) = match di {
+                4 => (0xa18, 16),
+                11 => (0x1d0, 0x20),
+                _ => (0, 0),
+            };
+            image.write_u32::<LittleEndian>(virtual_address).unwrap();
+            image.write_u32::<LittleEndian>(size).unwrap();
+        }
+        image.extend([0x33; 8]);
+
+        for (name, virtual_address, pointer_to_raw_data, pointer_to_linenumbers, number_of_linenumbers, characteristics) in [
+            (b".text\0\0\0", 0x1000, 0x400, 0xa12, 1, 0x60000020u32),
+            (b".data\0\0\0", 0x2000, 0x800, 0, 0, 0xc0000040),
+        ] {
+            image.extend(name);
+            image.write_u32::<LittleEndian>(0x10).unwrap();
+            image.write_u32::<LittleEndian>(virtual_address).unwrap();
+            image.write_u32::<LittleEndian>(0x200).unwrap();
+            image.write_u32::<LittleEndian>(pointer_to_raw_data).unwrap();
+            image.write_u32::<LittleEndian>(0).unwrap();
+            image.write_u32::<LittleEndian>(pointer_to_linenumbers).unwrap();
+            image.write_u16::<LittleEndian>(0).unwrap();
+            image.write_u16::<LittleEndian>(number_of_linenumbers).unwrap();
+            image.write_u32::<LittleEndian>(characteristics).unwrap();
+        }
+
+        image.resize(0x1d0, 0);
+        image.extend([0x44; 0x20]);
+        image.resize(0x400, 0);
+        image.resize(0x600, 0xcc);
+        image.resize(0x800, 0x55);
+        image.resize(0xa00, 0x66);
+
+        // A symbol, a line number and the certificate table.
+        image.extend([0x77; 18]);
+        image.extend([0x88; 6]);
+        image.write_u32::<LittleEndian>(16).unwrap();
+        image.write_u16::<LittleEndian>(0x200).unwrap();
+        image.write_u16::<LittleEndian>(2).unwrap();
+        image.extend([0x99; 8]);
+        image
+    }
+
+    fn round_trip(image_bytes: &[u8], finalize: bool) -> Vec<u8> {
+        let mut image = read_image(Cursor::new(image_bytes));
+        if finalize {
+            image.finalize();
+        }
+        let mut cursor = Cursor::new(Vec::new());
+        write_image(image, &mut cursor);
+        cursor.into_inner()
+    }
+
+    #[test]
+    fn round_trip_is_byte_exact() {
+        let image_bytes = synthetic_image();
+        assert_eq!(round_trip(&image_bytes, false), image_bytes);
+    }
+
+    #[test]
+    fn round_trip_through_finalize_is_byte_exact() {
+        let image_bytes = synthetic_image();
+        assert_eq!(round_trip(&image_bytes, true), image_bytes);
+    }
+
+    #[test]
+    fn address_translation_is_exact_at_section_boundaries() {
+        let image = read_image(Cursor::new(synthetic_image()));
+        assert_eq!(image.va_to_rva(0x401000), Some(0x1000));
+        assert_eq!(image.va_to_rva(0x3fffff), None);
+        assert_eq!(image.rva_to_va(0x1000), 0x401000);
+        assert_eq!(image.section_index_for_rva(0xfff), None);
+        assert_eq!(image.section_index_for_rva(0x1000), Some(0));
+        assert_eq!(image.section_index_for_rva(0x100f), Some(0));
+        // The raw data goes on, but VirtualSize ends the section.
+        assert_eq!(image.section_index_for_rva(0x1010), None);
+        assert_eq!(image.section_index_for_rva(0x2000), Some(1));
+        assert_eq!(image.rva_to_file_offset(0x1d0), Some(0x1d0));
+        assert_eq!(image.rva_to_file_offset(0x100f), Some(0x40f));
+        assert_eq!(image.rva_to_file_offset(0x2000), Some(0x800));
+        assert_eq!(image.file_offset_to_rva(0x40f), Some(0x100f));
+        assert_eq!(image.file_offset_to_rva(0x410), None);
+        assert_eq!(image.file_offset_to_rva(0x600), None);
+        assert!(image.is_initialized_data(0x100c, 4));
+        assert!(!image.is_initialized_data(0x100d, 4));
+    }
+
+    fn checksum_of(mut image_bytes: Vec<u8>) -> u32 {
+        write_checksum(&mut image_bytes);
+        u32::from_le_bytes(image_bytes[0xd8..0xdc].try_into().unwrap())
+    }
+
+    #[test]
+    fn checksum_matches_pefile() {
+        // Computed with pefile's generate_checksum, which skips the field
+        // rather than zeroing it.
+        let image_bytes = synthetic_image();
+        assert_eq!(checksum_of(image_bytes.clone()), 0x8bbb);
+        // An odd trailing byte counts as the low half of a word.
+        let mut odd_image_bytes = image_bytes;
+        odd_image_bytes.push(0xab);
+        assert_eq!(checksum_of(odd_image_bytes), 0x8c67);
+    }
+}
@@ -0,0 +1,105 @@
+use super::coff::{Section, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ};
+use super::pe::{DataDirectory, DirectoryEntry, Image};
+
+const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x02000000;
+
+const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+pub const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+
+const PAGE_SIZE: u32 = 0x1000;
+
+/// What to do about ASLR when linking into an image that has it.
+#[derive(Default, PartialEq)]
+pub enum Aslr {
+    /// Keep the image relocatable by adding base relocations.
+    #[default]
+    Relocate,
+    /// Clear DYNAMIC_BASE, so that the image always loads at its base.
+    Disable,
+}
+
+/// Whether the loader can move the image, which needs base relocations.
+pub fn is_relocatable(image: &Image) -> bool {
+    image.coff_header.characteristics & IMAGE_FILE_RELOCS_STRIPPED == 0
+        && image.directory(DirectoryEntry::BaseReloc).is_some()
+}
+
+/// Adds a HIGHLOW base relocation for every RVA in `sites` the image doesn't
+/// have one for yet. The existing base relocations and the new ones are
+/// written into a new ".reloc" section that the directory points to.
+/// Returns the number of base relocations added.
+pub fn add_base_relocations(image: &mut Image, sites: &[u32]) -> usize {
+    let mut relocations = read_base_relocations(image);
+    let existing_count = relocations.len();
+    relocations.extend(sites.iter().map(|&rva| (rva, IMAGE_REL_BASED_HIGHLOW)));
+    relocations.sort_by_key(|&(rva, _)| rva);
+    relocations.dedup_by_key(|&mut (rva, _)| rva);
+    let added = relocations.len() - existing_count;
+    if added == 0 {
+        return 0;
+    }
+
+    let raw_data = build_base_relocations(&relocations);
+    let size = u32::try_from(raw_data.len()).unwrap();
+    let si = image.append_section(Section {
+        name: ".reloc".to_string(),
+        raw_data,
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_DISCARDABLE | IMAGE_SCN_MEM_READ,
+        ..Default::default()
+    });
+    let virtual_address = image.sections[si].virtual_address;
+    image.set_directory(DirectoryEntry::BaseReloc, DataDirectory {virtual_address, size});
+    added
+}
+
+/// Reads the base relocations of the image as RVAs and types, leaving out
+/// the padding entries.
+pub fn read_base_relocations(image: &Image) -> Vec<(u32, u16)> {
+    let data = match image.directory_data(DirectoryEntry::BaseReloc) {
+        Some(d) => d,
+        None => return Vec::new(),
+    };
+    let mut relocations = Vec::new();
+    let mut position = 0;
+    while position + 8 <= data.len() {
+        let page_rva = u32::from_le_bytes(data[position..position + 4].try_into().unwrap());
+        let block_size = usize::try_from(u32::from_le_bytes(data[position + 4..position + 8].try_into().unwrap())).unwrap();
+        assert!(block_size >= 8 && position + block_size <= data.len(), "malformed base relocation block at {:#x}", page_rva);
+        for entry in data[position + 8..position + block_size].chunks_exact(2) {
+            let entry = u16::from_le_bytes(entry.try_into().unwrap());
+            let relocation_type = entry >> 12;
+            if relocation_type != IMAGE_REL_BASED_ABSOLUTE {
+                relocations.push((page_rva + u32::from(entry & 0x0fff), relocation_type));
+            }
+        }
+        position += block_size;
+    }
+    relocations
+}
+
+/// Builds the blocks of base relocations, one per 4K page, out of sorted
+/// RVAs and types. Blocks are padded to 4 bytes with an ABSOLUTE entry.
+fn build_base_relocations(relocations: &[(u32, u16)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut ri = 0;
+    while ri < relocations.len() {
+        let page_rva = relocations[ri].0 & !(PAGE_SIZE - 1);
+        let block_start = data.len();
+        data.extend(page_rva.to_le_bytes());
+        data.extend([0; 4]);
+        while ri < relocations.len() && relocations[ri].0 & !(PAGE_SIZE - 1) == page_rva {
+            let (rva, relocation_type) = relocations[ri];
+            let entry = (relocation_type << 12) | u16::try_from(rva - page_rva).unwrap();
+            data.extend(entry.to_le_bytes());
+            ri += 1;
+        }
+        if data.len() % 4 != 0 {
+            data.extend(IMAGE_REL_BASED_ABSOLUTE.to_le_bytes());
+        }
+        let block_size = u32::try_from(data.len() - block_start).unwrap();
+        data[block_start + 4..block_start + 8].copy_from_slice(&block_size.to_le_bytes());
+    }
+    data
+}
@@ -0,0 +1,888 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::coff::{Section, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ};
+use super::pe::{DataDirectory, DirectoryEntry, Image};
+
+/// Size of IMAGE_RESOURCE_DIRECTORY, which is followed by its entries.
+const RESOURCE_DIRECTORY_SIZE: usize = 16;
+const RESOURCE_DIRECTORY_ENTRY_SIZE: usize = 8;
+const RESOURCE_DATA_ENTRY_SIZE: usize = 16;
+/// Set in the Name of an entry that points at a string rather than being
+/// an ID.
+const RESOURCE_NAME_IS_STRING: u32 = 0x80000000;
+/// Set in the OffsetToData of an entry that points at another directory
+/// rather than at an IMAGE_RESOURCE_DATA_ENTRY.
+const RESOURCE_DATA_IS_DIRECTORY: u32 = 0x80000000;
+
+pub const RT_ICON: u16 = 3;
+pub const RT_STRING: u16 = 6;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+pub const RT_MANIFEST: u16 = 24;
+
+/// English (United States), what new resources get unless told otherwise.
+const DEFAULT_LANGUAGE: u16 = 0x0409;
+/// Unicode, the code page of the strings in a VERSIONINFO.
+const UNICODE_CODE_PAGE: u16 = 1200;
+
+/// A type or name in the resource tree. Named entries sort before the ones
+/// with an ID, the way the loader expects them.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ResourceName {
+    Name(String),
+    Id(u16),
+}
+
+/// A leaf of the resource tree.
+pub struct Resource {
+    pub resource_type: ResourceName,
+    pub name: ResourceName,
+    pub language: u16,
+    pub code_page: u32,
+    pub data: Vec<u8>,
+}
+
+impl Resource {
+    fn is(&self, resource_type: &ResourceName, name: &ResourceName, language: u16) -> bool {
+        self.resource_type == *resource_type && self.name == *name && self.language == language
+    }
+}
+
+/// Removes the section holding the resource directory from the end of the
+/// image, so that new sections can go in front of it. It has to be the last
+/// section, or be followed only by the base relocations, the way MSVC lays
+/// images out. Those move up into its place then, as nothing points into
+/// them but their directory.
+pub fn take_last_resource_section(image: &mut Image) -> Option<Section> {
+    let directory = image.directory(DirectoryEntry::Resource)?;
+    let resource_section_index = image.section_index_for_rva(directory.virtual_address)?;
+    let last_section_index = image.sections.len() - 1;
+    if resource_section_index == last_section_index {
+        return image.sections.pop();
+    }
+
+    let base_relocations = image.directory(DirectoryEntry::BaseReloc)?;
+    if resource_section_index + 1 != last_section_index || image.section_index_for_rva(base_relocations.virtual_address) != Some(last_section_index) {
+        return None;
+    }
+    let reloc_section = image.sections.pop().unwrap();
+    let resource_section = image.sections.pop();
+    let offset = base_relocations.virtual_address - reloc_section.virtual_address;
+    let si = image.append_section(reloc_section);
+    image.set_directory(DirectoryEntry::BaseReloc, DataDirectory {
+        virtual_address: image.sections[si].virtual_address + offset,
+        size: base_relocations.size,
+    });
+    resource_section
+}
+
+/// Appends a resource section taken with `take_last_resource_section` back
+/// to the image, after whatever was added in the meantime. The RVAs of the
+/// resource data and the resource directory are moved along with it.
+/// Returns the index of the section.
+pub fn append_resource_section(image: &mut Image, section: Section) -> usize {
+    let directory = image.directory(DirectoryEntry::Resource).unwrap();
+    let old_virtual_address = section.virtual_address;
+    let old_extent = section.virtual_size.max(u32::try_from(section.raw_data.len()).unwrap());
+    let si = image.append_section(section);
+    let new_virtual_address = image.sections[si].virtual_address;
+
+    let root_offset = directory.virtual_address - old_virtual_address;
+    let mut visited = Vec::new();
+    rebase_directory(
+        &mut image.sections[si].raw_data,
+        usize::try_from(root_offset).unwrap(),
+        0,
+        (old_virtual_address, old_virtual_address + old_extent),
+        new_virtual_address.wrapping_sub(old_virtual_address),
+        &mut visited,
+    );
+    image.set_directory(DirectoryEntry::Resource, DataDirectory {
+        virtual_address: new_virtual_address + root_offset,
+        size: directory.size,
+    });
+    si
+}
+
+/// Adds `delta` to the OffsetToData of every data entry below the directory
+/// at `offset`, if it points into `old_range`. Offsets in the tree are
+/// relative to the root directory at `root_offset` in `data`, so only the
+/// data entries need rebasing.
+fn rebase_directory(data: &mut [u8], root_offset: usize, offset: u32, old_range: (u32, u32), delta: u32, visited: &mut Vec<u32>) {
+    if visited.contains(&offset) {
+        return;
+    }
+    visited.push(offset);
+
+    for (_, offset_to_data) in read_entries(&data[root_offset..], offset) {
+        if offset_to_data & RESOURCE_DATA_IS_DIRECTORY != 0 {
+            rebase_directory(data, root_offset, offset_to_data & !RESOURCE_DATA_IS_DIRECTORY, old_range, delta, visited);
+            continue;
+        }
+        // Data entries may be shared between directories.
+        if visited.contains(&offset_to_data) {
+            continue;
+        }
+        visited.push(offset_to_data);
+        let data_entry_position = root_offset + usize::try_from(offset_to_data).unwrap();
+        let data_rva = read_u32(data, data_entry_position);
+        if data_rva >= old_range.0 && data_rva < old_range.1 {
+            data[data_entry_position..data_entry_position + 4].copy_from_slice(&data_rva.wrapping_add(delta).to_le_bytes());
+        }
+    }
+}
+
+/// Reads every resource of the image, in the order of the tree.
+pub fn read_resources(image: &Image) -> Vec<Resource> {
+    let directory = match image.directory(DirectoryEntry::Resource) {
+        Some(d) => d,
+        None => return Vec::new(),
+    };
+    let si = image.section_index_for_rva(directory.virtual_address).expect("the resource directory isn't in a section");
+    let section = &image.sections[si];
+    let tree = &section.raw_data[usize::try_from(directory.virtual_address - section.virtual_address).unwrap()..];
+
+    let mut resources = Vec::new();
+    for (resource_type, type_offset) in read_entries(tree, 0) {
+        assert_ne!(type_offset & RESOURCE_DATA_IS_DIRECTORY, 0, "resource type {:?} has no names", resource_type);
+        for (name, name_offset) in read_entries(tree, type_offset & !RESOURCE_DATA_IS_DIRECTORY) {
+            assert_ne!(name_offset & RESOURCE_DATA_IS_DIRECTORY, 0, "resource {:?} has no languages", name);
+            for (language, data_entry_offset) in read_entries(tree, name_offset & !RESOURCE_DATA_IS_DIRECTORY) {
+                let language = match language {
+                    ResourceName::Id(l) => l,
+                    ResourceName::Name(n) => panic!("resource {:?} has the language {}, languages are numbers", name, n),
+                };
+                let position = usize::try_from(data_entry_offset).unwrap();
+                let data_rva = read_u32(tree, position);
+                let size = read_u32(tree, position + 4);
+                let data = image.section_data(data_rva, size)
+                    .unwrap_or_else(|| panic!("the data of resource {:?} isn't in initialized data", name));
+                resources.push(Resource {
+                    resource_type: resource_type.clone(),
+                    name: name.clone(),
+                    language,
+                    code_page: read_u32(tree, position + 8),
+                    data: data.to_vec(),
+                });
+            }
+        }
+    }
+    resources
+}
+
+/// The entries of the directory at `offset` in `tree`, as their name and
+/// OffsetToData.
+fn read_entries(tree: &[u8], offset: u32) -> Vec<(ResourceName, u32)> {
+    let position = usize::try_from(offset).unwrap();
+    let number_of_named_entries = read_u16(tree, position + 12);
+    let number_of_id_entries = read_u16(tree, position + 14);
+    let mut entries = Vec::new();
+    for ei in 0..usize::from(number_of_named_entries) + usize::from(number_of_id_entries) {
+        let entry_position = position + RESOURCE_DIRECTORY_SIZE + ei * RESOURCE_DIRECTORY_ENTRY_SIZE;
+        let name = read_u32(tree, entry_position);
+        let name = if name & RESOURCE_NAME_IS_STRING != 0 {
+            let name_position = usize::try_from(name & !RESOURCE_NAME_IS_STRING).unwrap();
+            let length = usize::from(read_u16(tree, name_position));
+            ResourceName::Name(read_utf16(&tree[name_position + 2..name_position + 2 + length * 2]))
+        } else {
+            ResourceName::Id(u16::try_from(name).expect("a resource ID doesn't fit 16 bits"))
+        };
+        entries.push((name, read_u32(tree, entry_position + 4)));
+    }
+    entries
+}
+
+/// Replaces the resources of the image with `resources`, written to a new
+/// ".rsrc" section at the end of the image. The old resource section is
+/// left as it is; take it out with `take_last_resource_section` first, before
+/// anything else is appended, to drop it. Returns the index of the section.
+pub fn write_resources(image: &mut Image, resources: &[Resource]) -> usize {
+    let raw_data = build_resource_directory(resources);
+    let length = u32::try_from(raw_data.len()).unwrap();
+    let si = image.append_section(Section {
+        name: ".rsrc".to_string(),
+        raw_data,
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+        ..Default::default()
+    });
+    // The tree is built as though it were at RVA 0.
+    let virtual_address = image.sections[si].virtual_address;
+    rebase_directory(&mut image.sections[si].raw_data, 0, 0, (0, length), virtual_address, &mut Vec::new());
+    image.set_directory(DirectoryEntry::Resource, DataDirectory {virtual_address, size: length});
+    si
+}
+
+/// A name in the resource tree with its resources, one per language.
+type NameGroup<'a> = (&'a ResourceName, Vec<&'a Resource>);
+
+/// Lays out the resource tree the way cvtres does: the directories, then
+/// the data entries, the names and finally the data, aligned to 8 bytes.
+/// The data entries point at RVAs as if the tree started at RVA 0.
+fn build_resource_directory(resources: &[Resource]) -> Vec<u8> {
+    let mut sorted = resources.iter().collect::<Vec<&Resource>>();
+    sorted.sort_by(|a, b| (&a.resource_type, &a.name, a.language).cmp(&(&b.resource_type, &b.name, b.language)));
+    for pair in sorted.windows(2) {
+        assert!(!pair[1].is(&pair[0].resource_type, &pair[0].name, pair[0].language),
+                "resource {:?} {:?} is there twice in language {:#06x}", pair[0].resource_type, pair[0].name, pair[0].language);
+    }
+
+    let mut types: Vec<(&ResourceName, Vec<NameGroup>)> = Vec::new();
+    for resource in sorted {
+        match types.last_mut() {
+            Some((t, names)) if **t == resource.resource_type => match names.last_mut() {
+                Some((n, languages)) if **n == resource.name => languages.push(resource),
+                _ => names.push((&resource.name, vec![resource])),
+            },
+            _ => types.push((&resource.resource_type, vec![(&resource.name, vec![resource])])),
+        }
+    }
+
+    let directory_size = |entry_count: usize| RESOURCE_DIRECTORY_SIZE + entry_count * RESOURCE_DIRECTORY_ENTRY_SIZE;
+    let mut directories_size = directory_size(types.len());
+    let mut strings = Vec::new();
+    let mut string_offsets: Vec<(&ResourceName, usize)> = Vec::new();
+    for (resource_type, names) in &types {
+        directories_size += directory_size(names.len());
+        for (name, languages) in names {
+            directories_size += directory_size(languages.len());
+            for n in [*resource_type, *name] {
+                if let ResourceName::Name(s) = n {
+                    if !string_offsets.iter().any(|(o, _)| *o == n) {
+                        string_offsets.push((n, strings.len()));
+                        strings.extend(u16::try_from(s.encode_utf16().count()).unwrap().to_le_bytes());
+                        strings.extend(encode_utf16(s));
+                    }
+                }
+            }
+        }
+    }
+    let data_entries_offset = directories_size;
+    let strings_offset = data_entries_offset + resources.len() * RESOURCE_DATA_ENTRY_SIZE;
+    let data_offset = (strings_offset + strings.len()).div_ceil(8) * 8;
+
+    let mut tree = vec![0; data_offset];
+    tree[strings_offset..strings_offset + strings.len()].copy_from_slice(&strings);
+    let name_field = |name: &ResourceName| match name {
+        ResourceName::Name(_) => {
+            let (_, offset) = string_offsets.iter().find(|(n, _)| *n == name).unwrap();
+            RESOURCE_NAME_IS_STRING | u32::try_from(strings_offset + offset).unwrap()
+        }
+        ResourceName::Id(id) => u32::from(*id),
+    };
+
+    let mut next_directory = 0;
+    let mut next_data_entry = data_entries_offset;
+    let type_names = types.iter().map(|(t, _)| *t).collect::<Vec<&ResourceName>>();
+    let type_directory = allocate_directory(&mut tree, &mut next_directory, &type_names);
+    for (ti, (_, names)) in types.iter().enumerate() {
+        let name_names = names.iter().map(|(n, _)| *n).collect::<Vec<&ResourceName>>();
+        let name_directory = allocate_directory(&mut tree, &mut next_directory, &name_names);
+        write_entry(&mut tree, type_directory, ti, name_field(type_names[ti]), RESOURCE_DATA_IS_DIRECTORY | u32::try_from(name_directory).unwrap());
+        for (ni, (_, languages)) in names.iter().enumerate() {
+            let language_names = languages.iter().map(|r| ResourceName::Id(r.language)).collect::<Vec<ResourceName>>();
+            let language_directory = allocate_directory(&mut tree, &mut next_directory, &language_names.iter().collect::<Vec<&ResourceName>>());
+            write_entry(&mut tree, name_directory, ni, name_field(name_names[ni]), RESOURCE_DATA_IS_DIRECTORY | u32::try_from(language_directory).unwrap());
+            for (li, resource) in languages.iter().enumerate() {
+                write_entry(&mut tree, language_directory, li, u32::from(resource.language), u32::try_from(next_data_entry).unwrap());
+                let data_rva = u32::try_from(tree.len()).unwrap();
+                write_u32(&mut tree, next_data_entry, data_rva);
+                write_u32(&mut tree, next_data_entry + 4, u32::try_from(resource.data.len()).unwrap());
+                write_u32(&mut tree, next_data_entry + 8, resource.code_page);
+                next_data_entry += RESOURCE_DATA_ENTRY_SIZE;
+                tree.extend(&resource.data);
+                tree.resize(tree.len().div_ceil(8) * 8, 0);
+            }
+        }
+    }
+    tree
+}
+
+/// Writes the header of a directory with the entries `names` at
+/// `next_directory` and moves that past it. Returns where it went.
+fn allocate_directory(tree: &mut [u8], next_directory: &mut usize, names: &[&ResourceName]) -> usize {
+    let position = *next_directory;
+    let number_of_named_entries = names.iter().filter(|n| matches!(n, ResourceName::Name(_))).count();
+    let number_of_id_entries = names.len() - number_of_named_entries;
+    tree[position + 12..position + 14].copy_from_slice(&u16::try_from(number_of_named_entries).unwrap().to_le_bytes());
+    tree[position + 14..position + 16].copy_from_slice(&u16::try_from(number_of_id_entries).unwrap().to_le_bytes());
+    *next_directory += RESOURCE_DIRECTORY_SIZE + names.len() * RESOURCE_DIRECTORY_ENTRY_SIZE;
+    position
+}
+
+fn write_entry(tree: &mut [u8], directory: usize, index: usize, name: u32, offset_to_data: u32) {
+    let position = directory + RESOURCE_DIRECTORY_SIZE + index * RESOURCE_DIRECTORY_ENTRY_SIZE;
+    write_u32(tree, position, name);
+    write_u32(tree, position + 4, offset_to_data);
+}
+
+/// Applies the edits described by `edits_str` to `resources`. Files named
+/// there are relative to `base_directory`. Deletions go first, then raw
+/// replacements and additions, then the typed edits.
+pub fn edit_resources(resources: &mut Vec<Resource>, edits_str: &str, base_directory: &Path) {
+    let edits: ResourceEdits = toml::from_str(edits_str).unwrap();
+    let read_file = |file: &str| std::fs::read(base_directory.join(file)).unwrap_or_else(|e| panic!("could not read {}: {}", file, e));
+
+    for edit in edits.delete {
+        let resource_type = edit.resource_type.normalized();
+        let name = edit.name.map(ResourceName::normalized);
+        let count = resources.len();
+        resources.retain(|r| {
+            let matches = r.resource_type == resource_type
+                && name.as_ref().is_none_or(|n| r.name == *n)
+                && edit.language.is_none_or(|l| r.language == l);
+            !matches
+        });
+        assert_ne!(resources.len(), count, "there's no resource {:?} {:?} to delete", resource_type, name);
+    }
+    for edit in edits.replace {
+        let resource_type = edit.resource_type.normalized();
+        let name = edit.name.expect("a resource to replace needs a name").normalized();
+        let data = read_file(edit.file.as_deref().expect("a resource to replace needs a file"));
+        let mut replaced = false;
+        for resource in resources.iter_mut() {
+            if resource.resource_type == resource_type && resource.name == name && edit.language.is_none_or(|l| resource.language == l) {
+                resource.data = data.clone();
+                replaced = true;
+            }
+        }
+        assert!(replaced, "there's no resource {:?} {:?} to replace", resource_type, name);
+    }
+    for edit in edits.add {
+        let resource_type = edit.resource_type.normalized();
+        let name = edit.name.expect("a resource to add needs a name").normalized();
+        let language = edit.language.unwrap_or(DEFAULT_LANGUAGE);
+        assert!(!resources.iter().any(|r| r.is(&resource_type, &name, language)),
+                "resource {:?} {:?} is already there, replace it instead", resource_type, name);
+        resources.push(Resource {
+            resource_type,
+            name,
+            language,
+            code_page: 0,
+            data: read_file(edit.file.as_deref().expect("a resource to add needs a file")),
+        });
+    }
+
+    if let Some(version) = edits.version {
+        edit_version(resources, &version);
+    }
+    if let Some(manifest) = edits.manifest {
+        let name = manifest.name.map_or(ResourceName::Id(1), ResourceName::normalized);
+        set_resource(resources, ResourceName::Id(RT_MANIFEST), name, manifest.language, read_file(&manifest.file));
+    }
+    for string in edits.strings {
+        edit_string(resources, string.id, string.language.unwrap_or(DEFAULT_LANGUAGE), &string.text);
+    }
+    for icon in edits.icons {
+        let name = icon.name.normalized();
+        edit_icon_group(resources, name, icon.language.unwrap_or(DEFAULT_LANGUAGE), &read_file(&icon.file));
+    }
+}
+
+/// Replaces the data of the resource, or adds it if it isn't there. Without
+/// a language, whatever language the resource already has is kept.
+fn set_resource(resources: &mut Vec<Resource>, resource_type: ResourceName, name: ResourceName, language: Option<u16>, data: Vec<u8>) {
+    let existing = resources.iter_mut()
+        .find(|r| r.resource_type == resource_type && r.name == name && language.is_none_or(|l| r.language == l));
+    match existing {
+        Some(resource) => resource.data = data,
+        None => resources.push(Resource {
+            resource_type,
+            name,
+            language: language.unwrap_or(DEFAULT_LANGUAGE),
+            code_page: 0,
+            data,
+        }),
+    }
+}
+
+/// A node of a VERSIONINFO: VS_VERSIONINFO itself, a StringFileInfo, a
+/// StringTable, a String and so on all share this layout.
+struct VersionNode {
+    key: String,
+    /// 1 for text, 0 for binary.
+    value_type: u16,
+    value: Vec<u8>,
+    children: Vec<VersionNode>,
+}
+
+const VS_FIXEDFILEINFO_SIZE: usize = 52;
+const VS_FFI_SIGNATURE: u32 = 0xFEEF04BD;
+
+impl VersionNode {
+    fn text(key: &str, text: &str) -> VersionNode {
+        let mut value = encode_utf16(text);
+        value.extend([0, 0]);
+        VersionNode {key: key.to_string(), value_type: 1, value, children: Vec::new()}
+    }
+
+    fn child_mut(&mut self, key: &str) -> Option<&mut VersionNode> {
+        self.children.iter_mut().find(|c| c.key == key)
+    }
+}
+
+fn read_version_node(data: &[u8], position: usize) -> VersionNode {
+    let length = usize::from(read_u16(data, position));
+    let value_length = usize::from(read_u16(data, position + 2));
+    let value_type = read_u16(data, position + 4);
+    let end = (position + length).min(data.len());
+
+    let key_start = position + 6;
+    let mut key_end = key_start;
+    while key_end + 2 <= end && read_u16(data, key_end) != 0 {
+        key_end += 2;
+    }
+    let key = read_utf16(&data[key_start..key_end]);
+
+    let value_start = (key_end + 2).next_multiple_of(4);
+    // The length of text is in characters.
+    let value_size = if value_type == 1 { value_length * 2 } else { value_length };
+    let value_end = (value_start + value_size).min(end);
+    let value = data[value_start.min(end)..value_end].to_vec();
+
+    let mut children = Vec::new();
+    let mut child_position = value_end.next_multiple_of(4);
+    while child_position + 6 <= end {
+        let child_length = usize::from(read_u16(data, child_position));
+        if child_length == 0 {
+            break;
+        }
+        children.push(read_version_node(data, child_position));
+        child_position = (child_position + child_length).next_multiple_of(4);
+    }
+    VersionNode {key, value_type, value, children}
+}
+
+fn write_version_node(node: &VersionNode, data: &mut Vec<u8>) {
+    let position = data.len();
+    let value_length = if node.value_type == 1 { node.value.len() / 2 } else { node.value.len() };
+    data.extend([0, 0]);
+    data.extend(u16::try_from(value_length).unwrap().to_le_bytes());
+    data.extend(node.value_type.to_le_bytes());
+    data.extend(encode_utf16(&node.key));
+    data.extend([0, 0]);
+    data.resize(data.len().next_multiple_of(4), 0);
+    data.extend(&node.value);
+    for child in &node.children {
+        data.resize(data.len().next_multiple_of(4), 0);
+        write_version_node(child, data);
+    }
+    let length = u16::try_from(data.len() - position).expect("a VERSIONINFO node is too large");
+    data[position..position + 2].copy_from_slice(&length.to_le_bytes());
+}
+
+/// A VERSIONINFO for an application with version 0.0.0.0, no strings and
+/// the translation `language`, Unicode.
+fn new_version_info(language: u16) -> VersionNode {
+    let mut fixed_file_info = vec![0; VS_FIXEDFILEINFO_SIZE];
+    write_u32(&mut fixed_file_info, 0, VS_FFI_SIGNATURE);
+    // dwStrucVersion
+    write_u32(&mut fixed_file_info, 4, 0x00010000);
+    // dwFileFlagsMask, dwFileOS of VOS_NT_WINDOWS32 and dwFileType of VFT_APP
+    write_u32(&mut fixed_file_info, 24, 0x3F);
+    write_u32(&mut fixed_file_info, 32, 0x00040004);
+    write_u32(&mut fixed_file_info, 36, 1);
+
+    let mut translation = language.to_le_bytes().to_vec();
+    translation.extend(UNICODE_CODE_PAGE.to_le_bytes());
+    VersionNode {
+        key: "VS_VERSION_INFO".to_string(),
+        value_type: 0,
+        value: fixed_file_info,
+        children: vec![VersionNode {
+            key: "VarFileInfo".to_string(),
+            value_type: 1,
+            value: Vec::new(),
+            children: vec![VersionNode {key: "Translation".to_string(), value_type: 0, value: translation, children: Vec::new()}],
+        }],
+    }
+}
+
+/// Sets the version numbers and strings of the VERSIONINFO, creating one if
+/// there is none. New strings go in the first string table.
+fn edit_version(resources: &mut Vec<Resource>, edit: &VersionEdit) {
+    let version_type = ResourceName::Id(RT_VERSION);
+    let language = edit.language.unwrap_or(DEFAULT_LANGUAGE);
+    let index = match resources.iter().position(|r| r.resource_type == version_type && edit.language.is_none_or(|l| r.language == l)) {
+        Some(i) => i,
+        None => {
+            let mut data = Vec::new();
+            write_version_node(&new_version_info(language), &mut data);
+            resources.push(Resource {resource_type: version_type, name: ResourceName::Id(1), language, code_page: 0, data});
+            resources.len() - 1
+        }
+    };
+
+    let resource = &mut resources[index];
+    let mut root = read_version_node(&resource.data, 0);
+    assert!(root.value.len() >= VS_FIXEDFILEINFO_SIZE && read_u32(&root.value, 0) == VS_FFI_SIGNATURE,
+            "the VERSIONINFO has no VS_FIXEDFILEINFO");
+    if let Some(version) = &edit.file_version {
+        let (ms, ls) = parse_version(version);
+        write_u32(&mut root.value, 8, ms);
+        write_u32(&mut root.value, 12, ls);
+    }
+    if let Some(version) = &edit.product_version {
+        let (ms, ls) = parse_version(version);
+        write_u32(&mut root.value, 16, ms);
+        write_u32(&mut root.value, 20, ls);
+    }
+
+    if !edit.strings.is_empty() {
+        if root.child_mut("StringFileInfo").is_none() {
+            // Ahead of VarFileInfo, like rc puts it.
+            root.children.insert(0, VersionNode {key: "StringFileInfo".to_string(), value_type: 1, value: Vec::new(), children: Vec::new()});
+        }
+        let string_file_info = root.child_mut("StringFileInfo").unwrap();
+        if string_file_info.children.is_empty() {
+            string_file_info.children.push(VersionNode {
+                key: format!("{:04X}{:04X}", resource.language, UNICODE_CODE_PAGE),
+                value_type: 1,
+                value: Vec::new(),
+                children: Vec::new(),
+            });
+        }
+        let string_table = &mut string_file_info.children[0];
+        for (key, text) in &edit.strings {
+            match string_table.child_mut(key) {
+                Some(string) => *string = VersionNode::text(key, text),
+                None => string_table.children.push(VersionNode::text(key, text)),
+            }
+        }
+    }
+
+    resource.data.clear();
+    write_version_node(&root, &mut resource.data);
+}
+
+/// Parses "1.2.3.4" into the most and least significant halves of a
+/// version. Missing parts are zero.
+fn parse_version(version_str: &str) -> (u32, u32) {
+    let parts = version_str.split('.')
+        .map(|p| p.parse::<u16>().unwrap_or_else(|_| panic!("expected a version like 1.2.3.4, got {}", version_str)))
+        .collect::<Vec<u16>>();
+    assert!(!parts.is_empty() && parts.len() <= 4, "expected a version like 1.2.3.4, got {}", version_str);
+    let part = |i: usize| u32::from(parts.get(i).copied().unwrap_or(0));
+    ((part(0) << 16) | part(1), (part(2) << 16) | part(3))
+}
+
+/// Sets string `id` of the string table in `language`. Strings are stored
+/// in blocks of 16, block n + 1 holding the strings 16n to 16n + 15.
+fn edit_string(resources: &mut Vec<Resource>, id: u16, language: u16, text: &str) {
+    let string_type = ResourceName::Id(RT_STRING);
+    let block = ResourceName::Id(id / 16 + 1);
+    let mut strings = match resources.iter().find(|r| r.is(&string_type, &block, language)) {
+        Some(resource) => read_string_block(&resource.data),
+        None => vec![Vec::new(); 16],
+    };
+    strings[usize::from(id % 16)] = text.encode_utf16().collect();
+
+    let mut data = Vec::new();
+    for string in &strings {
+        data.extend(u16::try_from(string.len()).unwrap().to_le_bytes());
+        data.extend(string.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    set_resource(resources, string_type, block, Some(language), data);
+}
+
+/// The 16 strings of a string table block, each a length followed by that
+/// many UTF-16 characters.
+fn read_string_block(data: &[u8]) -> Vec<Vec<u16>> {
+    let mut strings = Vec::new();
+    let mut position = 0;
+    for _ in 0..16 {
+        let length = usize::from(read_u16(data, position));
+        position += 2;
+        strings.push((0..length).map(|i| read_u16(data, position + i * 2)).collect());
+        position += length * 2;
+    }
+    strings
+}
+
+const ICONDIR_SIZE: usize = 6;
+const ICONDIRENTRY_SIZE: usize = 16;
+const GRPICONDIRENTRY_SIZE: usize = 14;
+
+/// Replaces the icon group `name` in `language` with the icons of the .ico
+/// file `ico`, or adds it. The icons of the old group go away, the new ones
+/// get IDs after the highest one in use.
+fn edit_icon_group(resources: &mut Vec<Resource>, name: ResourceName, language: u16, ico: &[u8]) {
+    let icon_type = ResourceName::Id(RT_ICON);
+    let group_type = ResourceName::Id(RT_GROUP_ICON);
+    assert!(ico.len() >= ICONDIR_SIZE && read_u16(ico, 2) == 1, "the icon file of {:?} isn't an .ico", name);
+
+    if let Some(group) = resources.iter().find(|r| r.is(&group_type, &name, language)) {
+        let old_ids = (0..usize::from(read_u16(&group.data, 4)))
+            .map(|i| ResourceName::Id(read_u16(&group.data, ICONDIR_SIZE + i * GRPICONDIRENTRY_SIZE + 12)))
+            .collect::<Vec<ResourceName>>();
+        resources.retain(|r| !(r.resource_type == icon_type && r.language == language && old_ids.contains(&r.name)));
+    }
+
+    let first_id = resources.iter()
+        .filter_map(|r| match r.name {
+            ResourceName::Id(id) if r.resource_type == icon_type => Some(id),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0) + 1;
+    let count = read_u16(ico, 4);
+    let mut group_data = ico[..ICONDIR_SIZE].to_vec();
+    for (i, id) in (0..usize::from(count)).zip(first_id..) {
+        let entry = &ico[ICONDIR_SIZE + i * ICONDIRENTRY_SIZE..ICONDIR_SIZE + (i + 1) * ICONDIRENTRY_SIZE];
+        let size = usize::try_from(read_u32(entry, 8)).unwrap();
+        let offset = usize::try_from(read_u32(entry, 12)).unwrap();
+        resources.push(Resource {
+            resource_type: icon_type.clone(),
+            name: ResourceName::Id(id),
+            language,
+            code_page: 0,
+            data: ico[offset..offset + size].to_vec(),
+        });
+        // The group entry is the file's entry with the ID in place of the
+        // offset.
+        group_data.extend(&entry[..12]);
+        group_data.extend(id.to_le_bytes());
+    }
+    set_resource(resources, group_type, name, Some(language), group_data);
+}
+
+impl ResourceName {
+    /// Names are kept in upper case, like rc does, since that's how
+    /// FindResource looks them up.
+    fn normalized(self) -> ResourceName {
+        match self {
+            ResourceName::Name(n) => ResourceName::Name(n.to_uppercase()),
+            id => id,
+        }
+    }
+}
+
+fn encode_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn read_utf16(data: &[u8]) -> String {
+    let units = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<u16>>();
+    String::from_utf16_lossy(&units)
+}
+
+fn read_u16(data: &[u8], position: usize) -> u16 {
+    u16::from_le_bytes(data[position..position + 2].try_into().expect("the resource data is cut short"))
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes(data[position..position + 4].try_into().expect("the resource data is cut short"))
+}
+
+fn write_u32(data: &mut [u8], position: usize, value: u32) {
+    data[position..position + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[derive(Deserialize, Debug)]
+struct ResourceEdits {
+    #[serde(default)]
+    delete: Vec<RawResourceEdit>,
+    #[serde(default)]
+    replace: Vec<RawResourceEdit>,
+    #[serde(default)]
+    add: Vec<RawResourceEdit>,
+    version: Option<VersionEdit>,
+    manifest: Option<ManifestEdit>,
+    #[serde(default)]
+    strings: Vec<StringEdit>,
+    #[serde(default)]
+    icons: Vec<IconEdit>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawResourceEdit {
+    #[serde(rename = "type")]
+    resource_type: ResourceName,
+    name: Option<ResourceName>,
+    language: Option<u16>,
+    file: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VersionEdit {
+    file_version: Option<String>,
+    product_version: Option<String>,
+    language: Option<u16>,
+    #[serde(default)]
+    strings: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestEdit {
+    file: String,
+    name: Option<ResourceName>,
+    language: Option<u16>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StringEdit {
+    id: u16,
+    text: String,
+    language: Option<u16>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IconEdit {
+    name: ResourceName,
+    file: String,
+    language: Option<u16>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    use super::super::coff::Section;
+    use super::super::pe::tests::synthetic_image;
+    use super::super::pe::{read_image, Image};
+    use super::{
+        Resource,
+        ResourceName,
+        VersionEdit,
+        RT_MANIFEST,
+        RT_STRING,
+        RT_VERSION,
+        append_resource_section,
+        edit_string,
+        edit_version,
+        read_resources,
+        read_string_block,
+        read_u16,
+        read_u32,
+        read_version_node,
+        take_last_resource_section,
+        write_resources,
+    };
+
+    fn resource(resource_type: ResourceName, name: ResourceName, language: u16, data: &[u8]) -> Resource {
+        Resource {resource_type, name, language, code_page: 0, data: data.to_vec()}
+    }
+
+    fn sample_resources() -> Vec<Resource> {
+        vec![
+            resource(ResourceName::Id(RT_MANIFEST), ResourceName::Id(1), 0x409, b"<assembly/>"),
+            resource(ResourceName::Name("BLOB".to_string()), ResourceName::Name("FIRST".to_string()), 0x409, &[1, 2, 3]),
+            resource(ResourceName::Name("BLOB".to_string()), ResourceName::Name("FIRST".to_string()), 0x407, &[4, 5]),
+            resource(ResourceName::Name("BLOB".to_string()), ResourceName::Id(7), 0x409, &[6; 20]),
+        ]
+    }
+
+    type ResourceKey = (ResourceName, ResourceName, u16, Vec<u8>);
+
+    fn keys(image: &Image) -> Vec<ResourceKey> {
+        let mut keys = read_resources(image).into_iter()
+            .map(|r| (r.resource_type, r.name, r.language, r.data))
+            .collect::<Vec<ResourceKey>>();
+        keys.sort();
+        keys
+    }
+
+    fn sample_keys() -> Vec<ResourceKey> {
+        let mut keys = sample_resources().into_iter()
+            .map(|r| (r.resource_type, r.name, r.language, r.data))
+            .collect::<Vec<ResourceKey>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn written_resources_read_back() {
+        let mut image = read_image(Cursor::new(synthetic_image()));
+        write_resources(&mut image, &sample_resources());
+        assert_eq!(keys(&image), sample_keys());
+    }
+
+    #[test]
+    fn appending_the_resource_section_rebases_its_data() {
+        let mut image = read_image(Cursor::new(synthetic_image()));
+        write_resources(&mut image, &sample_resources());
+        let section = take_last_resource_section(&mut image).unwrap();
+        let old_virtual_address = section.virtual_address;
+        // Something else where the resources were, to read wrong data from.
+        image.append_section(Section {
+            name: ".data".to_string(),
+            raw_data: vec![0xee; section.raw_data.len()],
+            ..Default::default()
+        });
+        let si = append_resource_section(&mut image, section);
+        assert_ne!(image.sections[si].virtual_address, old_virtual_address);
+        assert_eq!(keys(&image), sample_keys());
+    }
+
+    #[test]
+    fn replacing_a_string_keeps_the_rest_of_its_block() {
+        let mut resources = Vec::new();
+        edit_string(&mut resources, 17, 0x409, "first");
+        edit_string(&mut resources, 18, 0x409, "second");
+        edit_string(&mut resources, 17, 0x409, "replaced");
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].resource_type, ResourceName::Id(RT_STRING));
+        // Strings 16 to 31 are in block 2.
+        assert_eq!(resources[0].name, ResourceName::Id(2));
+
+        let strings = read_string_block(&resources[0].data);
+        assert_eq!(strings.len(), 16);
+        assert!(strings[0].is_empty());
+        assert_eq!(String::from_utf16(&strings[1]).unwrap(), "replaced");
+        assert_eq!(String::from_utf16(&strings[2]).unwrap(), "second");
+        assert!(strings[3..].iter().all(|s| s.is_empty()));
+    }
+
+    /// Checks that the VERSIONINFO node at `position` and everything in it
+    /// starts on a 4-byte boundary and that the lengths add up.
+    fn check_version_node(data: &[u8], position: usize) -> usize {
+        assert_eq!(position % 4, 0, "a VERSIONINFO node at {:#x} isn't aligned", position);
+        let length = usize::from(read_u16(data, position));
+        let value_length = usize::from(read_u16(data, position + 2));
+        let value_type = read_u16(data, position + 4);
+        let mut key_end = position + 6;
+        while read_u16(data, key_end) != 0 {
+            key_end += 2;
+        }
+        let value_start = (key_end + 2).next_multiple_of(4);
+        let value_size = if value_type == 1 { value_length * 2 } else { value_length };
+        let mut child_position = (value_start + value_size).next_multiple_of(4);
+        while child_position < position + length {
+            let child_end = check_version_node(data, child_position);
+            assert!(child_end <= position + length, "a VERSIONINFO node at {:#x} runs past its parent", child_position);
+            child_position = child_end.next_multiple_of(4);
+        }
+        position + length
+    }
+
+    #[test]
+    fn editing_the_version_keeps_nodes_aligned() {
+        let mut resources = Vec::new();
+        let mut edit = VersionEdit {
+            file_version: Some("1.2.3.4".to_string()),
+            product_version: None,
+            language: None,
+            strings: BTreeMap::from([("CompanyName".to_string(), "Odd".to_string())]),
+        };
+        edit_version(&mut resources, &edit);
+        edit.file_version = None;
+        edit.strings = BTreeMap::from([("FileDescription".to_string(), "A".to_string())]);
+        edit_version(&mut resources, &edit);
+
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].resource_type, ResourceName::Id(RT_VERSION));
+        let data = &resources[0].data;
+        assert_eq!(check_version_node(data, 0), data.len());
+
+        let root = read_version_node(data, 0);
+        assert_eq!(read_u32(&root.value, 8), 0x00010002);
+        assert_eq!(read_u32(&root.value, 12), 0x00030004);
+        let string_table = &root.children.iter().find(|c| c.key == "StringFileInfo").unwrap().children[0];
+        let strings = string_table.children.iter().map(|s| s.key.as_str()).collect::<Vec<&str>>();
+        assert_eq!(strings, ["CompanyName", "FileDescription"]);
+    }
+}
@@ -0,0 +1,264 @@
+use super::coff::{
+    COFF,
+    Section,
+    SymbolTableRecord,
+    IMAGE_SCN_CNT_INITIALIZED_DATA,
+    IMAGE_SCN_ALIGN_MASK,
+    IMAGE_SCN_MEM_READ,
+    IMAGE_SCN_MEM_WRITE,
+    IMAGE_SYM_CLASS_STATIC,
+    Symbol,
+    create_symbol_for_table,
+    insert_section,
+};
+use super::pe::{DataDirectory, DirectoryEntry, Image};
+use super::reloc::{IMAGE_REL_BASED_HIGHLOW, read_base_relocations};
+
+const TLS_DIRECTORY_SIZE: u32 = 24;
+
+/// Offsets of the fields of IMAGE_TLS_DIRECTORY32.
+const START_ADDRESS_OF_RAW_DATA_OFFSET: u32 = 0;
+const END_ADDRESS_OF_RAW_DATA_OFFSET: u32 = 4;
+const ADDRESS_OF_INDEX_OFFSET: u32 = 8;
+const ADDRESS_OF_CALL_BACKS_OFFSET: u32 = 12;
+const SIZE_OF_ZERO_FILL_OFFSET: u32 = 16;
+
+/// The variable the loader stores the TLS slot of the image in.
+const TLS_INDEX_NAME: &str = "__tls_index";
+
+/// Name of the output section the TLS template of the object goes into.
+pub const TLS_SECTION_NAME: &str = ".tls";
+
+/// The static symbol that marks where the image's template ends up.
+const TEMPLATE_SYMBOL_NAME: &str = ".tls$template";
+
+pub fn has_tls_sections(coff: &COFF) -> bool {
+    coff.sections.iter().any(|s| is_tls_section(&s.name))
+}
+
+fn is_tls_section(name: &str) -> bool {
+    let name = name.trim_end_matches('\0');
+    name == TLS_SECTION_NAME || name.starts_with(".tls$")
+}
+
+/// Adds the TLS template of the image to the object as its first section,
+/// named ".tls", which sorts ahead of the object's own ".tls" and ".tls$"
+/// sections. The output section starts with the image's template and the
+/// variables already in it keep their offsets. The zero fill becomes part of the raw data. Returns
+/// the offsets into the template of the addresses the image has base
+/// relocations for, for `template_sites`.
+pub fn prepend_template(image: &Image, coff: &mut COFF) -> Vec<u32> {
+    let directory = match image.directory(DirectoryEntry::Tls) {
+        Some(d) => d,
+        None => return Vec::new(),
+    };
+    let start = read_va_field(image, directory.virtual_address + START_ADDRESS_OF_RAW_DATA_OFFSET);
+    let end = read_va_field(image, directory.virtual_address + END_ADDRESS_OF_RAW_DATA_OFFSET);
+    let size_of_zero_fill = image.read_u32(directory.virtual_address + SIZE_OF_ZERO_FILL_OFFSET);
+    let mut raw_data = image.section_data(start, end - start).expect("the TLS template isn't in initialized data").to_vec();
+    raw_data.resize(raw_data.len() + usize::try_from(size_of_zero_fill).unwrap(), 0);
+    if raw_data.is_empty() {
+        return Vec::new();
+    }
+    let offsets = read_base_relocations(image).into_iter()
+        .filter(|&(rva, relocation_type)| relocation_type == IMAGE_REL_BASED_HIGHLOW && rva >= start && rva < end)
+        .map(|(rva, _)| rva - start)
+        .collect();
+
+    let characteristics = coff.sections.iter()
+        .filter(|s| is_tls_section(&s.name))
+        .map(|s| s.characteristics & !IMAGE_SCN_ALIGN_MASK)
+        .next()
+        .unwrap_or(IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE);
+    // The template is as aligned as the start of the section it was in.
+    let alignment = (1u32 << start.trailing_zeros().min(13)).max(4);
+    insert_section(coff, 0, Section {
+        name: TLS_SECTION_NAME.to_string(),
+        raw_data,
+        characteristics: characteristics | ((alignment.trailing_zeros() + 1) << 20),
+        ..Default::default()
+    });
+    coff.symbols.push(SymbolTableRecord::Symbol(Symbol {
+        name: TEMPLATE_SYMBOL_NAME.to_string(),
+        value: 0,
+        section_number: 1,
+        symbol_type: 0,
+        storage_class: IMAGE_SYM_CLASS_STATIC,
+        number_of_aux_symbols: 0,
+    }));
+    offsets
+}
+
+/// The RVAs the `offsets` into the image's template moved to, once the
+/// object's sections are in the image. They need base relocations again.
+pub fn template_sites(image: &Image, object_symbols: &[SymbolTableRecord], offsets: &[u32]) -> Vec<u32> {
+    let template_rva = object_symbols.iter().find_map(|symbol| match symbol {
+        SymbolTableRecord::Symbol(s) if s.name == TEMPLATE_SYMBOL_NAME && s.section_number > 0 => {
+            Some(image.sections[usize::try_from(s.section_number - 1).unwrap()].virtual_address + s.value)
+        }
+        _ => None,
+    });
+    match template_rva {
+        Some(rva) => offsets.iter().map(|offset| rva + offset).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Points the TLS directory at the template in the image section
+/// `template_section`, if there is one, and appends `callbacks` to the TLS
+/// callbacks, creating the directory if the image has none. Defines the TLS
+/// index variable for the object, unless something already does. Returns
+/// the RVAs of the absolute addresses written, which need base relocations.
+pub fn update_tls_directory(image: &mut Image, symbol_table: &mut Vec<SymbolTableRecord>, template_section: Option<usize>, callbacks: &[u32]) -> Vec<u32> {
+    let directory_rva = match image.directory(DirectoryEntry::Tls) {
+        Some(d) => d.virtual_address,
+        None => create_tls_directory(image),
+    };
+    let mut absolute_sites = Vec::new();
+
+    if let Some(si) = template_section {
+        let section = &image.sections[si];
+        let start = image.rva_to_va(section.virtual_address);
+        let end = start + section.virtual_size;
+        image.write_u32(directory_rva + START_ADDRESS_OF_RAW_DATA_OFFSET, start);
+        image.write_u32(directory_rva + END_ADDRESS_OF_RAW_DATA_OFFSET, end);
+        image.write_u32(directory_rva + SIZE_OF_ZERO_FILL_OFFSET, 0);
+    }
+
+    if !callbacks.is_empty() {
+        // The callbacks are a null-terminated array of addresses.
+        let mut callback_vas = Vec::new();
+        let mut callback_rva = read_va_field(image, directory_rva + ADDRESS_OF_CALL_BACKS_OFFSET);
+        if callback_rva != 0 {
+            loop {
+                let callback_va = image.read_u32(callback_rva);
+                if callback_va == 0 {
+                    break;
+                }
+                callback_vas.push(callback_va);
+                callback_rva += 4;
+            }
+        }
+        callback_vas.extend(callbacks.iter().map(|&rva| image.rva_to_va(rva)));
+        callback_vas.push(0);
+
+        let si = image.append_section(Section {
+            name: ".rdata".to_string(),
+            raw_data: callback_vas.iter().flat_map(|va| va.to_le_bytes()).collect(),
+            characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+            ..Default::default()
+        });
+        let array_rva = image.sections[si].virtual_address;
+        absolute_sites.extend((0..callback_vas.len() - 1).map(|ci| array_rva + u32::try_from(ci * 4).unwrap()));
+        image.write_u32(directory_rva + ADDRESS_OF_CALL_BACKS_OFFSET, image.rva_to_va(array_rva));
+    }
+
+    for offset in [START_ADDRESS_OF_RAW_DATA_OFFSET, END_ADDRESS_OF_RAW_DATA_OFFSET, ADDRESS_OF_INDEX_OFFSET, ADDRESS_OF_CALL_BACKS_OFFSET] {
+        if image.read_u32(directory_rva + offset) != 0 {
+            absolute_sites.push(directory_rva + offset);
+        }
+    }
+
+    let is_index_defined = symbol_table.iter().any(|symbol| match symbol {
+        SymbolTableRecord::Symbol(s) => s.name == TLS_INDEX_NAME && s.section_number > 0,
+        SymbolTableRecord::Aux(_) => false,
+    });
+    if !is_index_defined {
+        let index_rva = read_va_field(image, directory_rva + ADDRESS_OF_INDEX_OFFSET);
+        let si = image.section_index_for_rva(index_rva).expect("the TLS index isn't in a section");
+        let value = index_rva - image.sections[si].virtual_address;
+        symbol_table.push(create_symbol_for_table(TLS_INDEX_NAME.to_string(), value, i16::try_from(si + 1).unwrap(), false));
+    }
+    absolute_sites
+}
+
+/// Appends a section holding an empty TLS directory followed by the TLS
+/// index, points the data directory at it and returns its RVA.
+fn create_tls_directory(image: &mut Image) -> u32 {
+    let si = image.append_section(Section {
+        name: ".data".to_string(),
+        raw_data: vec![0; usize::try_from(TLS_DIRECTORY_SIZE + 4).unwrap()],
+        characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+        ..Default::default()
+    });
+    let directory_rva = image.sections[si].virtual_address;
+    // An empty template, right where the directory is.
+    let directory_va = image.rva_to_va(directory_rva);
+    image.write_u32(directory_rva + START_ADDRESS_OF_RAW_DATA_OFFSET, directory_va);
+    image.write_u32(directory_rva + END_ADDRESS_OF_RAW_DATA_OFFSET, directory_va);
+    image.write_u32(directory_rva + ADDRESS_OF_INDEX_OFFSET, directory_va + TLS_DIRECTORY_SIZE);
+    image.set_directory(DirectoryEntry::Tls, DataDirectory {virtual_address: directory_rva, size: TLS_DIRECTORY_SIZE});
+    directory_rva
+}
+
+/// Reads an address field and returns it as an RVA, or 0 if it's null.
+fn read_va_field(image: &Image, rva: u32) -> u32 {
+    match image.read_u32(rva) {
+        0 => 0,
+        va => image.va_to_rva(va).expect("a TLS address is below the image base"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::super::coff::{
+        COFF,
+        Section,
+        SymbolTableRecord,
+        IMAGE_SCN_CNT_INITIALIZED_DATA,
+        IMAGE_SCN_MEM_READ,
+        IMAGE_SCN_MEM_WRITE,
+        create_symbol_for_table,
+    };
+    use super::super::layout::append_sections;
+    use super::super::pe::tests::synthetic_image;
+    use super::super::pe::{read_image, DataDirectory, DirectoryEntry};
+    use super::{
+        prepend_template,
+        START_ADDRESS_OF_RAW_DATA_OFFSET,
+        END_ADDRESS_OF_RAW_DATA_OFFSET,
+        ADDRESS_OF_INDEX_OFFSET,
+        ADDRESS_OF_CALL_BACKS_OFFSET,
+        SIZE_OF_ZERO_FILL_OFFSET,
+    };
+
+    const IMAGE_SCN_ALIGN_4BYTES: u32 = 0x00300000;
+
+    #[test]
+    fn image_template_goes_ahead_of_a_plain_tls_section() {
+        let mut image = read_image(Cursor::new(synthetic_image()));
+        // A TLS directory at the start of .data, with an 8-byte template of
+        // 0x55 bytes further in.
+        image.sections[1].virtual_size = 0x200;
+        image.write_u32(0x2000 + START_ADDRESS_OF_RAW_DATA_OFFSET, 0x402100);
+        image.write_u32(0x2000 + END_ADDRESS_OF_RAW_DATA_OFFSET, 0x402108);
+        image.write_u32(0x2000 + ADDRESS_OF_INDEX_OFFSET, 0x402180);
+        image.write_u32(0x2000 + ADDRESS_OF_CALL_BACKS_OFFSET, 0);
+        image.write_u32(0x2000 + SIZE_OF_ZERO_FILL_OFFSET, 0);
+        image.section_data_mut(0x2100, 8).unwrap().fill(0x55);
+        image.set_directory(DirectoryEntry::Tls, DataDirectory {virtual_address: 0x2000, size: 24});
+
+        let mut coff = COFF {
+            sections: vec![Section {
+                name: ".tls".to_string(),
+                raw_data: vec![0xaa; 4],
+                characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE | IMAGE_SCN_ALIGN_4BYTES,
+                ..Default::default()
+            }],
+            symbols: vec![create_symbol_for_table("_variable".to_string(), 0, 1, false)],
+        };
+        prepend_template(&image, &mut coff);
+        let first_object_section = image.sections.len();
+        append_sections(&mut image, &mut coff, 0, &[], &[]);
+
+        let section = &image.sections[first_object_section];
+        assert_eq!(section.name, ".tls");
+        assert_eq!(section.raw_data[..12], [0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0xaa, 0xaa, 0xaa, 0xaa]);
+        match &coff.symbols[0] {
+            SymbolTableRecord::Symbol(s) => assert_eq!(s.value, 8),
+            SymbolTableRecord::Aux(_) => unreachable!(),
+        }
+    }
+}